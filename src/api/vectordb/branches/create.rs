@@ -0,0 +1,39 @@
+use crate::{
+    api_service::create_vector_store_branch,
+    models::{
+        common::WaCustomError,
+        rpc::{CreateBranch, RPCResponseBody},
+        types::get_app_env,
+    },
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/branches`
+pub(crate) async fn create(
+    database_name: web::Path<String>,
+    web::Json(body): web::Json<CreateBranch>,
+) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    let parent_branch = body.parent_branch.as_deref().unwrap_or("main");
+    match create_vector_store_branch(
+        vec_store.clone(),
+        &body.branch_name,
+        parent_branch,
+        body.parent_version,
+    ) {
+        Ok(branch) => HttpResponse::Ok().json(RPCResponseBody::RespCreateBranch { branch }),
+        Err(WaCustomError::NotFound(msg)) => {
+            HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Err(WaCustomError::InvalidParams) => HttpResponse::BadRequest()
+            .body("Branch names must be unique and made of letters, digits, `_` and `-`"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,19 @@
+use crate::{
+    api_service::list_vector_store_branches,
+    models::{rpc::RPCResponseBody, types::get_app_env},
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/branches`
+pub(crate) async fn list(database_name: web::Path<String>) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    let branches = list_vector_store_branches(vec_store.clone());
+    HttpResponse::Ok().json(RPCResponseBody::RespListBranches { branches })
+}
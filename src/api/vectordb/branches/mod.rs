@@ -0,0 +1,5 @@
+mod create;
+mod list;
+
+pub(crate) use create::create;
+pub(crate) use list::list;
@@ -0,0 +1,42 @@
+use crate::{
+    api_service::{acquire_write_lock, delete_vector_store, stop_collection_writes},
+    models::{common::WaCustomError, types::get_app_env},
+};
+use actix_web::{web, HttpResponse};
+use cosdata::config_loader::Config;
+use std::time::Duration;
+
+// Route: `/vectordb/collections/{collection_name}`
+pub(crate) async fn delete(
+    collection_name: web::Path<String>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let name = collection_name.into_inner();
+    let Some(vec_store) = env.vector_store_map.get(&name).map(|store| store.clone()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    if let Err(e) = stop_collection_writes(&vec_store) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+
+    // wait for an on-going upsert to finish
+    let timeout = Duration::from_millis(config.write_lock_timeout_ms);
+    let permit = match acquire_write_lock(&vec_store, timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return HttpResponse::Conflict().body(e.to_string()),
+    };
+
+    match web::block(move || delete_vector_store(&name, permit)).await {
+        Ok(Ok(())) => HttpResponse::NoContent().finish(),
+        Ok(Err(WaCustomError::NotFound(_))) => {
+            HttpResponse::NotFound().body("Vector store not found")
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,16 @@
+use crate::{
+    api_service::get_collection,
+    models::{common::WaCustomError, rpc::RPCResponseBody},
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/collections/{collection_name}`
+pub(crate) async fn get(collection_name: web::Path<String>) -> HttpResponse {
+    match get_collection(&collection_name.into_inner()) {
+        Ok(collection) => {
+            HttpResponse::Ok().json(RPCResponseBody::RespGetCollection { collection })
+        }
+        Err(WaCustomError::NotFound(_)) => HttpResponse::NotFound().body("Vector store not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,12 @@
+use crate::{api_service::list_collections, models::rpc::RPCResponseBody};
+use actix_web::HttpResponse;
+
+// Route: `/vectordb/collections`
+pub(crate) async fn list() -> HttpResponse {
+    match list_collections() {
+        Ok(collections) => {
+            HttpResponse::Ok().json(RPCResponseBody::RespListCollections { collections })
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
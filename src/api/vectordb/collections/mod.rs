@@ -0,0 +1,7 @@
+mod delete;
+mod get;
+mod list;
+
+pub(crate) use delete::delete;
+pub(crate) use get::get;
+pub(crate) use list::list;
@@ -2,7 +2,11 @@ use actix_web::{web, HttpResponse};
 
 use crate::{
     api_service::init_vector_store,
-    models::rpc::{CreateVectorDb, RPCResponseBody},
+    models::{
+        rpc::{CreateVectorDb, RPCResponseBody},
+        types::{DistanceMetric, QuantizationMetric, MAX_CACHE_LEVEL},
+    },
+    quantization::StorageType,
 };
 
 // Route: `/vectordb/createdb`
@@ -12,13 +16,49 @@ pub(crate) async fn create(web::Json(body): web::Json<CreateVectorDb>) -> HttpRe
     let size = body.dimensions as usize;
     let lower_bound = body.min_val;
     let upper_bound = body.max_val;
-    // ---------------------------
-    // -- TODO Maximum cache level
-    // ---------------------------
-    let max_cache_level = 5;
+    let distance_metric = body.distance_metric.unwrap_or(DistanceMetric::Cosine);
+    let quantization_metric = body.quantization.unwrap_or(QuantizationMetric::Scalar);
+    let storage_type = body.storage_type.unwrap_or(StorageType::UnsignedByte);
+    let payload_indexes = body.payload_indexes.unwrap_or_default();
+    let max_cache_level = body.max_level.unwrap_or(5);
+    let hnsw_params = body.hnsw_params.unwrap_or_default();
+
+    if let QuantizationMetric::Product(_) = quantization_metric {
+        return HttpResponse::BadRequest().body("Product quantization is not supported yet");
+    }
+
+    if !hnsw_params.is_valid() {
+        return HttpResponse::BadRequest().body(
+            "m, ef_construction and ef_search must be positive, and level_multiplier above 1",
+        );
+    }
+
+    if max_cache_level > MAX_CACHE_LEVEL {
+        return HttpResponse::BadRequest()
+            .body(format!("max_level must be at most {}", MAX_CACHE_LEVEL));
+    }
+
+    if !distance_metric.supports_storage_type(storage_type) {
+        return HttpResponse::BadRequest().body(format!(
+            "Distance metric {:?} is not supported with storage type {:?}",
+            distance_metric, storage_type
+        ));
+    }
 
     // Call init_vector_store using web::block
-    let result = init_vector_store(name, size, lower_bound, upper_bound, max_cache_level).await;
+    let result = init_vector_store(
+        name,
+        size,
+        lower_bound,
+        upper_bound,
+        max_cache_level,
+        quantization_metric,
+        distance_metric,
+        storage_type,
+        payload_indexes,
+        hnsw_params,
+    )
+    .await;
 
     match result {
         Ok(_) => HttpResponse::Ok().json(RPCResponseBody::RespCreateVectorDb { result: true }),
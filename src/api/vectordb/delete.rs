@@ -0,0 +1,48 @@
+use actix_web::{web, HttpResponse};
+
+use crate::{
+    api_service::{acquire_write_lock, run_delete},
+    models::{
+        rpc::{DeleteVectors, RPCResponseBody},
+        types::{get_app_env, VectorId},
+    },
+};
+use cosdata::config_loader::Config;
+use std::time::Duration;
+
+// Route: `/vectordb/delete`
+pub(crate) async fn delete(
+    web::Json(body): web::Json<DeleteVectors>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let vec_store = match env.vector_store_map.get(&body.vector_db_name) {
+        Some(store) => store,
+        None => return HttpResponse::NotFound().body("Vector store not found"),
+    }
+    .clone();
+
+    // wait for an on-going upsert or transaction to finish
+    let timeout = Duration::from_millis(config.write_lock_timeout_ms);
+    let permit = match acquire_write_lock(&vec_store, timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return HttpResponse::Conflict().body(e.to_string()),
+    };
+
+    let vector_ids = body.vector_ids.into_iter().map(VectorId::from).collect();
+    let result = web::block(move || {
+        let result = run_delete(vec_store, vector_ids);
+        drop(permit);
+        result
+    })
+    .await;
+
+    match result {
+        Ok(Ok(deleted)) => HttpResponse::Ok().json(RPCResponseBody::RespDeleteVectors { deleted }),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
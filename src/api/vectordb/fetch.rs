@@ -1,6 +1,7 @@
 use crate::{
-    api_service::fetch_vector_neighbors,
+    api_service::{fetch_vector, resolve_version},
     models::{
+        common::WaCustomError,
         rpc::{FetchNeighbors, RPCResponseBody, Vector, VectorIdValue},
         types::{get_app_env, VectorId},
     },
@@ -21,31 +22,62 @@ pub(crate) async fn fetch(web::Json(body): web::Json<FetchNeighbors>) -> HttpRes
             return HttpResponse::InternalServerError().body("Vector store not found");
         }
     };
-    let fvid = VectorId::from(body.vector_id);
 
-    let result = fetch_vector_neighbors(vec_store.clone(), fvid).await;
+    let vector_ids: Vec<VectorIdValue> =
+        body.vector_id.into_iter().chain(body.vector_ids).collect();
+    if vector_ids.is_empty() {
+        return HttpResponse::BadRequest().body("Either vector_id or vector_ids must be provided");
+    }
 
-    let mut xx: Vec<Option<RPCResponseBody>> = result
-        .iter()
-        .map(|res_item| {
-            res_item.as_ref().map(|(vect, neig)| {
-                let nvid = VectorIdValue::from(vect.clone());
-                let response_data = RPCResponseBody::RespFetchNeighbors {
-                    neighbors: neig
-                        .iter()
-                        .map(|(vid, x)| (VectorIdValue::from(vid.clone()), x.clone()))
-                        .collect(),
-                    vector: Vector {
-                        id: nvid,
-                        values: vec![],
-                    },
-                };
-                response_data
-            })
-        })
-        .collect();
-    // Filter out any None values (optional)
-    xx.retain(|x| x.is_some());
-    let rs: Vec<RPCResponseBody> = xx.into_iter().map(|x| x.unwrap()).collect();
+    let branch = body.branch.as_deref().unwrap_or("main");
+    if vec_store
+        .version_hasher
+        .lock()
+        .unwrap()
+        .branch(branch)
+        .is_none()
+    {
+        return HttpResponse::NotFound().body("Branch not found");
+    }
+
+    let version = match body
+        .version
+        .as_ref()
+        .map(|selector| resolve_version(vec_store.clone(), branch, selector))
+        .transpose()
+    {
+        Ok(version) => version,
+        Err(WaCustomError::NotFound(msg)) => {
+            return HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    // ids without a stored vector are left out of the response
+    let mut rs: Vec<RPCResponseBody> = Vec::with_capacity(vector_ids.len());
+    for vector_id in vector_ids {
+        let fvid = VectorId::from(vector_id);
+        let (embedding, values, metadata, neighbors) =
+            match fetch_vector(vec_store.clone(), fvid, branch, version).await {
+                Ok(Some(result)) => result,
+                Ok(None) => continue,
+                Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+            };
+
+        // without the original values only the quantized storage is returned
+        let storage = (*embedding.raw_vec).clone();
+        rs.push(RPCResponseBody::RespFetchNeighbors {
+            neighbors: neighbors
+                .into_iter()
+                .map(|(vid, x)| (VectorIdValue::from(vid), x))
+                .collect(),
+            vector: Vector {
+                id: VectorIdValue::from(embedding.hash_vec),
+                values: values.unwrap_or_default(),
+                metadata,
+            },
+            storage,
+        });
+    }
     HttpResponse::Ok().json(rs)
 }
@@ -0,0 +1,22 @@
+use crate::{
+    api_service::index_build,
+    models::{rpc::RPCResponseBody, types::get_app_env},
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/index`
+// Progress of the last index build
+pub(crate) async fn build(database_name: web::Path<String>) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    match index_build(vec_store.clone()) {
+        Some(build) => HttpResponse::Ok().json(RPCResponseBody::RespIndexBuild { build }),
+        None => HttpResponse::NotFound().body("No index build was started"),
+    }
+}
@@ -0,0 +1,28 @@
+use crate::{
+    api_service::cancel_index_build,
+    models::{common::WaCustomError, rpc::RPCResponseBody, types::get_app_env},
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/index`
+// Stops the running index build, the current graph is kept
+pub(crate) async fn cancel(database_name: web::Path<String>) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    match cancel_index_build(vec_store.clone()) {
+        Ok(build) => HttpResponse::Accepted().json(RPCResponseBody::RespIndexBuild { build }),
+        Err(WaCustomError::NotFound(_)) => {
+            HttpResponse::NotFound().body("No index build was started")
+        }
+        Err(WaCustomError::InvalidParams) => {
+            HttpResponse::Conflict().body("The index build is not running")
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
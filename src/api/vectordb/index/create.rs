@@ -0,0 +1,43 @@
+use crate::{
+    api_service::start_index_build,
+    models::{
+        common::WaCustomError,
+        rpc::{CreateIndex, RPCResponseBody},
+        types::{get_app_env, MAX_CACHE_LEVEL},
+    },
+};
+use actix_web::{web, HttpResponse};
+use cosdata::config_loader::Config;
+
+// Route: `/vectordb/{database_name}/index`
+// Builds a new graph from every stored vector in the background, the body is
+// optional. Searches and writes go on while it runs
+pub(crate) async fn create(
+    database_name: web::Path<String>,
+    body: Option<web::Json<CreateIndex>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env
+        .vector_store_map
+        .get(&database_name.into_inner())
+        .map(|store| store.clone())
+    else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    let params = body.map(|body| body.into_inner()).unwrap_or_default();
+    match start_index_build(vec_store, params, config.upload_process_batch_size) {
+        Ok(build) => HttpResponse::Accepted().json(RPCResponseBody::RespIndexBuild { build }),
+        Err(WaCustomError::LockError(msg)) => HttpResponse::Conflict().body(msg),
+        Err(WaCustomError::InvalidParams) => HttpResponse::BadRequest().body(format!(
+            "m, ef_construction and ef_search must be positive, level_multiplier above 1, \
+             and max_cache_level at most {}",
+            MAX_CACHE_LEVEL
+        )),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,9 @@
+mod build;
+mod cancel;
+mod create;
+mod status;
+
+pub(crate) use build::build;
+pub(crate) use cancel::cancel;
+pub(crate) use create::create;
+pub(crate) use status::status;
@@ -0,0 +1,21 @@
+use crate::{
+    api_service::index_status,
+    models::{rpc::RPCResponseBody, types::get_app_env},
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/index/status`
+pub(crate) async fn status(database_name: web::Path<String>) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    match index_status(vec_store.clone()) {
+        Ok(status) => HttpResponse::Ok().json(RPCResponseBody::RespIndexStatus { status }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -1,11 +1,17 @@
 mod create;
+mod delete;
 mod fetch;
 mod search;
 mod upsert;
 
+pub(crate) mod branches;
+pub(crate) mod collections;
+pub(crate) mod index;
 pub(crate) mod transactions;
+pub(crate) mod versions;
 
 pub(crate) use create::create;
+pub(crate) use delete::delete;
 pub(crate) use fetch::fetch;
 pub(crate) use search::search;
 pub(crate) use upsert::upsert;
@@ -1,14 +1,18 @@
 use actix_web::{web, HttpResponse};
 
 use crate::{
-    api_service::ann_vector_query,
+    api_service::{ann_vector_query, resolve_version},
     convert_option_vec,
     models::{
+        common::WaCustomError,
         rpc::{RPCResponseBody, VectorANN},
         types::get_app_env,
     },
 };
 
+// Number of results returned when the request doesn't specify `nn_count`
+const DEFAULT_NN_COUNT: usize = 5;
+
 // Route: `/vectordb/search`
 pub(crate) async fn search(web::Json(body): web::Json<VectorANN>) -> HttpResponse {
     let env = match get_app_env() {
@@ -24,13 +28,72 @@ pub(crate) async fn search(web::Json(body): web::Json<VectorANN>) -> HttpRespons
         }
     };
 
-    let result = match ann_vector_query(vec_store.clone(), body.vector).await {
+    let k = match body.nn_count {
+        Some(nn_count) if nn_count <= 0 => {
+            return HttpResponse::BadRequest().body("nn_count must be a positive number")
+        }
+        Some(nn_count) => nn_count as usize,
+        None => DEFAULT_NN_COUNT,
+    };
+
+    if body.ef_search == Some(0) {
+        return HttpResponse::BadRequest().body("ef_search must be a positive number");
+    }
+
+    let branch = body.branch.as_deref().unwrap_or("main");
+    if vec_store
+        .version_hasher
+        .lock()
+        .unwrap()
+        .branch(branch)
+        .is_none()
+    {
+        return HttpResponse::NotFound().body("Branch not found");
+    }
+
+    let version = match body
+        .version
+        .as_ref()
+        .map(|selector| resolve_version(vec_store.clone(), branch, selector))
+        .transpose()
+    {
+        Ok(version) => version,
+        Err(WaCustomError::NotFound(msg)) => {
+            return HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
+    };
+
+    let result = match ann_vector_query(
+        vec_store.clone(),
+        body.vector,
+        k,
+        body.ef_search,
+        body.filter.as_ref(),
+        branch,
+        version,
+    )
+    .await
+    {
         Ok(result) => result,
         Err(err) => return HttpResponse::InternalServerError().body(err.to_string()),
     };
 
+    // the metadata is kept apart from `knn`, so that its format stays the same
+    let (knn, metadata) = match result {
+        Some(result) => {
+            let (knn, metadata): (Vec<_>, Vec<_>) = result
+                .into_iter()
+                .map(|(id, score, metadata)| ((id, score), metadata))
+                .unzip();
+            (Some(knn), Some(metadata))
+        }
+        None => (None, None),
+    };
+
     let response_data = RPCResponseBody::RespVectorKNN {
-        knn: convert_option_vec(result),
+        knn: convert_option_vec(knn),
+        metadata,
     };
     HttpResponse::Ok().json(response_data)
 }
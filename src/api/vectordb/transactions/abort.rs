@@ -1,4 +1,7 @@
-use crate::models::types::get_app_env;
+use crate::{
+    api_service::abort_transaction,
+    models::{common::WaCustomError, types::get_app_env},
+};
 use actix_web::{web, HttpResponse};
 
 // Route: `/vectordb/{database_name}/transactions/{transaction_id}/abort`
@@ -12,19 +15,9 @@ pub(crate) async fn abort(path_data: web::Path<(String, String)>) -> HttpRespons
         return HttpResponse::NotFound().body("Vector store not found");
     };
 
-    let mut cot_arc = vec_store.current_open_transaction.clone();
-    let cot = cot_arc.get();
-    let Some(transaction) = cot.as_ref() else {
-        return HttpResponse::NotFound().body("Transaction not found");
-    };
-
-    if transaction.hash != transaction_id {
-        return HttpResponse::NotFound().body("Transaction not found");
+    match abort_transaction(&vec_store, &transaction_id) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(WaCustomError::NotFound(_)) => HttpResponse::NotFound().body("Transaction not found"),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
     }
-
-    cot_arc.update(None);
-
-    // TODO: delete the wal file?
-
-    HttpResponse::Ok().finish()
 }
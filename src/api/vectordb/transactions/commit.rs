@@ -1,8 +1,15 @@
-use crate::models::types::get_app_env;
+use crate::{
+    api_service::commit_transaction,
+    models::{common::WaCustomError, types::get_app_env},
+};
 use actix_web::{web, HttpResponse};
+use cosdata::config_loader::Config;
 
 // Route: `/vectordb/{database_name}/transactions/{transaction_id}/commit`
-pub(crate) async fn commit(path_data: web::Path<(String, String)>) -> HttpResponse {
+pub(crate) async fn commit(
+    path_data: web::Path<(String, String)>,
+    config: web::Data<Config>,
+) -> HttpResponse {
     let (database_name, transaction_id) = path_data.into_inner();
     let env = match get_app_env() {
         Ok(env) => env,
@@ -11,10 +18,10 @@ pub(crate) async fn commit(path_data: web::Path<(String, String)>) -> HttpRespon
     let Some(vec_store) = env.vector_store_map.get(&database_name) else {
         return HttpResponse::NotFound().body("Vector store not found");
     };
+    let vec_store = vec_store.clone();
 
     let mut cot_arc = vec_store.current_open_transaction.clone();
-    let cot = cot_arc.get();
-    let Some(transaction) = cot.as_ref() else {
+    let Some(transaction) = cot_arc.get().clone() else {
         return HttpResponse::NotFound().body("Transaction not found");
     };
 
@@ -22,10 +29,19 @@ pub(crate) async fn commit(path_data: web::Path<(String, String)>) -> HttpRespon
         return HttpResponse::NotFound().body("Transaction not found");
     }
 
-    vec_store.current_version.clone().update(cot.clone());
-    cot_arc.update(None);
+    let result = web::block(move || {
+        commit_transaction(vec_store, transaction, config.upload_process_batch_size)
+    })
+    .await;
 
-    // TODO: update the data (nodes) in memory
-
-    HttpResponse::Ok().finish()
+    match result {
+        Ok(Ok(_)) => HttpResponse::Ok().finish(),
+        Ok(Err(WaCustomError::NotFound(_))) => {
+            HttpResponse::NotFound().body("Transaction not found")
+        }
+        // the transaction is still open if it couldn't be applied, and the
+        // commit can be retried. If only indexing it failed, it's committed
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
 }
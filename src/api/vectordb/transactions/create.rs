@@ -1,32 +1,61 @@
-use crate::models::{types::get_app_env, versioning::VersionHasher};
+use crate::api_service::{acquire_write_lock, expire_idle_transaction, open_transaction};
+use crate::models::{rpc::CreateTransaction, types::get_app_env};
 use actix_web::{web, HttpResponse};
+use cosdata::config_loader::Config;
 use serde_json::json;
+use std::time::Duration;
 
 // Route: `/vectordb/{database_name}/transactions`
-pub(crate) async fn create(database_name: web::Path<String>) -> HttpResponse {
+// The body is optional, `{"branch": "..."}` names the branch to open the
+// transaction on, "main" by default
+pub(crate) async fn create(
+    database_name: web::Path<String>,
+    body: Option<web::Json<CreateTransaction>>,
+    config: web::Data<Config>,
+) -> HttpResponse {
     let env = match get_app_env() {
         Ok(env) => env,
         Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
     };
 
-    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+    let Some(vec_store) = env
+        .vector_store_map
+        .get(&database_name.into_inner())
+        .map(|store| store.clone())
+    else {
         return HttpResponse::NotFound().body("Vector store not found");
     };
 
-    let mut cot_arc = vec_store.current_open_transaction.clone();
-
-    if cot_arc.get().is_some() {
-        return HttpResponse::Conflict()
-            .body("Cannot create a new transaction while there's an on-going transaction");
+    let branch = body
+        .and_then(|body| body.into_inner().branch)
+        .unwrap_or_else(|| "main".to_string());
+    if vec_store
+        .version_hasher
+        .lock()
+        .unwrap()
+        .branch(&branch)
+        .is_none()
+    {
+        return HttpResponse::NotFound().body("Branch not found");
     }
 
-    let ver = vec_store.get_current_version().unwrap();
-    let new_ver = ver.version + 1;
-    let mut hasher = VersionHasher::new();
-    let hash = hasher.generate_hash("main", new_ver, None, None);
-    let transaction_id = hash.hash.clone();
+    // the permit is held until the transaction is committed or aborted
+    let timeout = Duration::from_millis(config.write_lock_timeout_ms);
+    let permit = match acquire_write_lock(&vec_store, timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return HttpResponse::Conflict().body(e.to_string()),
+    };
+
+    let transaction_id = match open_transaction(&vec_store, &branch, permit) {
+        Ok(hash) => hash.hash,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
 
-    cot_arc.update(Some(hash));
+    actix_web::rt::spawn(expire_idle_transaction(
+        vec_store.clone(),
+        transaction_id.clone(),
+        Duration::from_millis(config.transaction_idle_timeout_ms),
+    ));
 
     HttpResponse::Ok().json(json!({
         "transaction_id": transaction_id
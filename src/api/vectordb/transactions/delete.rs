@@ -1,8 +1,18 @@
-use crate::models::types::get_app_env;
+use crate::{
+    api_service::stage_operations,
+    models::{
+        common::WaCustomError,
+        rpc::TransactionVectorIds,
+        types::{get_app_env, StagedOperation, VectorId},
+    },
+};
 use actix_web::{web, HttpResponse};
 
 // Route: `/vectordb/{database_name}/transactions/{transaction_id}/delete`
-pub(crate) async fn delete(path_data: web::Path<(String, String)>) -> HttpResponse {
+pub(crate) async fn delete(
+    path_data: web::Path<(String, String)>,
+    web::Json(body): web::Json<TransactionVectorIds>,
+) -> HttpResponse {
     let (database_name, transaction_id) = path_data.into_inner();
     let env = match get_app_env() {
         Ok(env) => env,
@@ -12,5 +22,19 @@ pub(crate) async fn delete(path_data: web::Path<(String, String)>) -> HttpRespon
         return HttpResponse::NotFound().body("Vector store not found");
     };
 
-    todo!()
+    let operations = body
+        .vector_ids
+        .into_iter()
+        .map(|id| StagedOperation::Delete {
+            id: VectorId::from(id),
+        })
+        .collect();
+
+    match stage_operations(vec_store.clone(), &transaction_id, operations) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(WaCustomError::NotFound(msg)) => {
+            HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
 }
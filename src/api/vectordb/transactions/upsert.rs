@@ -1,8 +1,18 @@
-use crate::models::types::get_app_env;
+use crate::{
+    api_service::stage_operations,
+    models::{
+        common::WaCustomError,
+        rpc::TransactionVectors,
+        types::{get_app_env, StagedOperation, VectorId},
+    },
+};
 use actix_web::{web, HttpResponse};
 
 // Route: `/vectordb/{database_name}/transactions/{transaction_id}/upsert`
-pub(crate) async fn upsert(path_data: web::Path<(String, String)>) -> HttpResponse {
+pub(crate) async fn upsert(
+    path_data: web::Path<(String, String)>,
+    web::Json(body): web::Json<TransactionVectors>,
+) -> HttpResponse {
     let (database_name, transaction_id) = path_data.into_inner();
     let env = match get_app_env() {
         Ok(env) => env,
@@ -12,5 +22,25 @@ pub(crate) async fn upsert(path_data: web::Path<(String, String)>) -> HttpRespon
         return HttpResponse::NotFound().body("Vector store not found");
     };
 
-    todo!()
+    let operations = body
+        .vectors
+        .into_iter()
+        .map(|vector| StagedOperation::Upsert {
+            id: VectorId::from(vector.id),
+            values: vector.values,
+            metadata: vector.metadata,
+        })
+        .collect();
+
+    match stage_operations(vec_store.clone(), &transaction_id, operations) {
+        Ok(_) => HttpResponse::Ok().finish(),
+        Err(WaCustomError::InvalidParams) => HttpResponse::BadRequest().body(format!(
+            "Vectors must have {} dimensions",
+            vec_store.dimensions
+        )),
+        Err(WaCustomError::NotFound(msg)) => {
+            HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
 }
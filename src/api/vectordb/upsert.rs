@@ -1,14 +1,15 @@
 use actix_web::{web, HttpResponse};
 
 use crate::{
-    api_service::run_upload,
+    api_service::{acquire_write_lock, run_upload},
     convert_vectors,
     models::{
-        rpc::{RPCResponseBody, UpsertVectors},
+        rpc::{InsertStats, RPCResponseBody, UpsertVectors},
         types::get_app_env,
     },
 };
 use cosdata::config_loader::Config;
+use std::time::Duration;
 
 // Route: `/vectordb/upsert`
 pub(crate) async fn upsert(web::Json(body): web::Json<UpsertVectors>,  config: web::Data<Config>) -> HttpResponse {
@@ -26,17 +27,30 @@ pub(crate) async fn upsert(web::Json(body): web::Json<UpsertVectors>,  config: w
     }
     .clone();
 
-    if vec_store.current_open_transaction.clone().get().is_some() {
-        return HttpResponse::Conflict()
-            .body("Cannot upsert while there's an on-going transaction");
-    }
+    // wait for an on-going upsert or transaction to finish
+    let timeout = Duration::from_millis(config.write_lock_timeout_ms);
+    let permit = match acquire_write_lock(&vec_store, timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return HttpResponse::Conflict().body(e.to_string()),
+    };
 
     // Call run_upload with the extracted parameters
-    web::block(move || {
-        let __result = run_upload(vec_store, convert_vectors(body.vectors),  config);
+    let result = web::block(move || {
+        let result = run_upload(vec_store, convert_vectors(body.vectors),  config);
+        drop(permit);
+        result
     })
-    .await
-    .unwrap();
-    let response_data = RPCResponseBody::RespUpsertVectors { insert_stats: None };
+    .await;
+    let counts = match result {
+        Ok(Ok(counts)) => counts,
+        Ok(Err(e)) => return HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let response_data = RPCResponseBody::RespUpsertVectors {
+        insert_stats: Some(InsertStats {
+            inserted: counts.inserted,
+            updated: counts.updated,
+        }),
+    };
     HttpResponse::Ok().json(response_data)
 }
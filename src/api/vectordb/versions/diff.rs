@@ -0,0 +1,35 @@
+use crate::{
+    api_service::diff_versions,
+    models::{
+        common::WaCustomError,
+        rpc::{RPCResponseBody, VersionSelector},
+        types::get_app_env,
+    },
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/versions/{from}/diff/{to}`
+pub(crate) async fn diff(path_data: web::Path<(String, String, String)>) -> HttpResponse {
+    let (database_name, from, to) = path_data.into_inner();
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    let result = diff_versions(
+        vec_store.clone(),
+        &VersionSelector::from(from),
+        &VersionSelector::from(to),
+    );
+
+    match result {
+        Ok(diff) => HttpResponse::Ok().json(RPCResponseBody::RespVersionDiff { diff }),
+        Err(WaCustomError::NotFound(msg)) => {
+            HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
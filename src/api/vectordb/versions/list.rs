@@ -0,0 +1,21 @@
+use crate::{
+    api_service::list_versions,
+    models::{rpc::RPCResponseBody, types::get_app_env},
+};
+use actix_web::{web, HttpResponse};
+
+// Route: `/vectordb/{database_name}/versions`
+pub(crate) async fn list(database_name: web::Path<String>) -> HttpResponse {
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env.vector_store_map.get(&database_name.into_inner()) else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    match list_versions(vec_store.clone()) {
+        Ok(versions) => HttpResponse::Ok().json(RPCResponseBody::RespListVersions { versions }),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,7 @@
+mod diff;
+mod list;
+mod rollback;
+
+pub(crate) use diff::diff;
+pub(crate) use list::list;
+pub(crate) use rollback::rollback;
@@ -0,0 +1,65 @@
+use crate::{
+    api_service::{acquire_write_lock, rollback_to_version},
+    models::{
+        common::WaCustomError,
+        rpc::{RPCResponseBody, VersionSelector},
+        types::get_app_env,
+    },
+};
+use actix_web::{web, HttpResponse};
+use cosdata::config_loader::Config;
+use std::time::Duration;
+
+// Route: `/vectordb/{database_name}/versions/{version}/rollback`
+pub(crate) async fn rollback(
+    path_data: web::Path<(String, String)>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    let (database_name, version) = path_data.into_inner();
+    let env = match get_app_env() {
+        Ok(env) => env,
+        Err(_) => return HttpResponse::InternalServerError().body("Env initialization error"),
+    };
+    let Some(vec_store) = env
+        .vector_store_map
+        .get(&database_name)
+        .map(|store| store.clone())
+    else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    // waits for the ongoing upsert or transaction like any other write
+    let timeout = Duration::from_millis(config.write_lock_timeout_ms);
+    let permit = match acquire_write_lock(&vec_store, timeout).await {
+        Ok(permit) => permit,
+        Err(e) => return HttpResponse::Conflict().body(e.to_string()),
+    };
+    // an index build that held the lock may have swapped in a new graph
+    let Some(vec_store) = env
+        .vector_store_map
+        .get(&database_name)
+        .map(|store| store.clone())
+    else {
+        return HttpResponse::NotFound().body("Vector store not found");
+    };
+
+    let target = VersionSelector::from(version);
+    let result = web::block(move || {
+        let result = rollback_to_version(vec_store, &target, config.upload_process_batch_size);
+        drop(permit);
+        result
+    })
+    .await;
+
+    match result {
+        Ok(Ok(version)) => HttpResponse::Ok().json(RPCResponseBody::RespRollback { version }),
+        Ok(Err(WaCustomError::NotFound(msg))) => {
+            HttpResponse::NotFound().body(format!("Not found: {}", msg))
+        }
+        Ok(Err(WaCustomError::InvalidParams)) => {
+            HttpResponse::BadRequest().body("The collection is already at that version")
+        }
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
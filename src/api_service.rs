@@ -1,26 +1,46 @@
+use crate::models::cache_loader::NodeRegistry;
 use crate::models::common::*;
 use crate::models::custom_buffered_writer::CustomBufferedWriter;
 use crate::models::file_persist::*;
+use crate::models::filter::SearchFilter;
 use crate::models::lazy_load::*;
 use crate::models::meta_persist::*;
-use crate::models::rpc::VectorIdValue;
+use crate::models::payload_index::PayloadIndexConfig;
+use crate::models::rpc::{
+    BranchDetails, CollectionInfo, CreateIndex, Filter, IndexBuild, IndexBuildState, IndexStatus,
+    Metadata, VectorIdValue, VersionDiff, VersionSelector,
+};
 use crate::models::types::*;
 use crate::models::user::Statistics;
+use crate::models::versioning::{
+    BranchInfo, OperationCounts, VersionHash, VersionHasher, VersionInfo,
+};
+use crate::models::wal::*;
 use crate::quantization::{Quantization, StorageType};
 use crate::vector_store::*;
 use actix_web::web;
 use arcshift::ArcShift;
 use cosdata::config_loader::Config;
-use lmdb::{DatabaseFlags, Transaction};
 use rand::Rng;
-use rayon::iter::IntoParallelIterator;
+use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
-use std::array::TryFromSliceError;
 use std::cell::RefCell;
-use std::fs::OpenOptions;
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, remove_dir_all, remove_file, rename, File, OpenOptions};
 use std::io::Write;
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
 
 pub async fn init_vector_store(
     name: String,
@@ -28,13 +48,73 @@ pub async fn init_vector_store(
     lower_bound: Option<f32>,
     upper_bound: Option<f32>,
     max_cache_level: u8,
+    quantization_metric: QuantizationMetric,
+    distance_metric: DistanceMetric,
+    storage_type: StorageType,
+    payload_indexes: Vec<PayloadIndexConfig>,
+    hnsw_params: HnswParams,
 ) -> Result<(), WaCustomError> {
-    if name.is_empty() {
+    // the name is used as the collection's directory name
+    if !is_valid_name(&name) {
+        return Err(WaCustomError::InvalidParams);
+    }
+
+    let ain_env = get_app_env()?;
+    if ain_env.vector_store_map.contains_key(&name) {
         return Err(WaCustomError::InvalidParams);
     }
 
-    let quantization_metric = Arc::new(QuantizationMetric::Scalar);
-    let storage_type = StorageType::UnsignedByte;
+    let collection_path = ain_env.collection_path(&name);
+    let vec_store = create_vector_store(
+        name.clone(),
+        collection_path,
+        size,
+        lower_bound,
+        upper_bound,
+        max_cache_level,
+        quantization_metric.clone(),
+        distance_metric,
+        storage_type,
+        payload_indexes.clone(),
+        hnsw_params,
+    )?;
+
+    store_collection_config(
+        ain_env.persist.clone(),
+        ain_env.collections_db.clone(),
+        &CollectionConfig {
+            name: name.clone(),
+            dimensions: size,
+            max_cache_level,
+            quantization_metric,
+            distance_metric,
+            storage_type,
+            payload_indexes,
+            hnsw_params,
+        },
+    )?;
+
+    ain_env.vector_store_map.insert(name, vec_store);
+
+    Ok(())
+}
+
+// Creates the files of a new collection in `collection_path`, with an empty
+// graph and version 0 of "main" as its current version
+pub fn create_vector_store(
+    name: String,
+    collection_path: PathBuf,
+    size: usize,
+    lower_bound: Option<f32>,
+    upper_bound: Option<f32>,
+    max_cache_level: u8,
+    quantization_metric: QuantizationMetric,
+    distance_metric: DistanceMetric,
+    storage_type: StorageType,
+    payload_indexes: Vec<PayloadIndexConfig>,
+    hnsw_params: HnswParams,
+) -> Result<Arc<VectorStore>, WaCustomError> {
+    create_dir_all(&collection_path).map_err(|e| WaCustomError::FsError(e.to_string()))?;
 
     let min = lower_bound.unwrap_or(-1.0);
     let max = upper_bound.unwrap_or(1.0);
@@ -57,7 +137,7 @@ pub async fn init_vector_store(
         OpenOptions::new()
             .create(true)
             .append(true)
-            .open("prop.data")
+            .open(collection_path.join("prop.data"))
             .expect("Failed to open file for writing"),
     );
 
@@ -65,23 +145,79 @@ pub async fn init_vector_store(
         OpenOptions::new()
             .create(true)
             .append(true)
-            .open("0.index")
+            .open(collection_path.join("0.index"))
             .expect("Failed to open file for writing"),
     ));
 
     let mut writer =
         CustomBufferedWriter::new(ver_file.clone()).expect("Failed opening custom buffer");
 
+    let prop_location = write_prop_to_file(
+        &NodeProp {
+            id: vec_hash.clone(),
+            value: vector_list.clone(),
+            location: None,
+        },
+        &prop_file,
+    );
+    let prop = Arc::new(NodeProp {
+        id: vec_hash.clone(),
+        value: vector_list.clone(),
+        location: Some(prop_location),
+    });
+
+    let root = create_root_chain(prop, max_cache_level, &mut writer);
+
+    writer
+        .flush()
+        .expect("Final Custom Buffered Writer flush failed ");
+    let lp = Arc::new(generate_tuples(
+        hnsw_params.level_multiplier,
+        max_cache_level,
+    ));
+
+    let vec_store = Arc::new(VectorStore::new(
+        exec_queue_nodes,
+        max_cache_level,
+        name.clone(),
+        collection_path.clone(),
+        root,
+        lp,
+        size,
+        (size / 32) as usize,
+        prop_file,
+        MetaDb::from_path(&collection_path)?,
+        ArcShift::new(None),
+        Arc::new(quantization_metric.clone()),
+        Arc::new(distance_metric),
+        storage_type,
+        payload_indexes,
+        hnsw_params,
+    ));
+
+    let version_hash = store_current_version(
+        vec_store.clone(),
+        "main".to_string(),
+        0,
+        OperationCounts::default(),
+    )?;
+    vec_store.set_current_version(Some(version_hash));
+
+    Ok(vec_store)
+}
+
+// Creates the chain of root nodes, one per level from `max_cache_level` down
+// to 0, and writes it to the index file. Returns the level 0 root
+fn create_root_chain(
+    prop: Arc<NodeProp>,
+    max_cache_level: u8,
+    writer: &mut CustomBufferedWriter,
+) -> LazyItemRef<MergedNode> {
     let mut root: LazyItemRef<MergedNode> = LazyItemRef::new_invalid();
     let mut prev: LazyItemRef<MergedNode> = LazyItemRef::new_invalid();
 
     let mut nodes = Vec::new();
     for l in (0..=max_cache_level).rev() {
-        let prop = Arc::new(NodeProp {
-            id: vec_hash.clone(),
-            value: vector_list.clone(),
-            location: Some((FileOffset(0), BytesToRead(0))),
-        });
         let mut current_node = ArcShift::new(MergedNode {
             hnsw_level: HNSWLevel(l as u8),
             prop: ArcShift::new(PropState::Ready(prop.clone())),
@@ -105,13 +241,11 @@ pub async fn init_vector_store(
 
         if l == 0 {
             root = nn.clone();
-            let _prop_location = write_prop_to_file(&prop, &prop_file);
-            current_node.get().set_prop_ready(prop);
         }
         nodes.push(nn.clone());
     }
     for (l, nn) in nodes.iter_mut().enumerate() {
-        match persist_node_update_loc(&mut writer, &mut nn.item) {
+        match persist_node_update_loc(writer, &mut nn.item) {
             Ok(_) => (),
             Err(e) => {
                 eprintln!("Failed node persist (init) for node {}: {}", l, e);
@@ -119,133 +253,820 @@ pub async fn init_vector_store(
         };
     }
 
-    writer
-        .flush()
-        .expect("Final Custom Buffered Writer flush failed ");
-    // ---------------------------
-    // -- TODO level entry ratio
-    // ---------------------------
-    let factor_levels = 10.0;
-    let lp = Arc::new(generate_tuples(factor_levels, max_cache_level));
-    let ain_env = get_app_env().map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    root
+}
 
-    let denv = ain_env.persist.clone();
+// Rebuilds the `VectorStore` of every persisted collection, must be called
+// before the server starts accepting requests. A collection that fails to load
+// is logged and skipped, the others are still loaded
+pub fn load_collections(config: &Config) -> Result<(), WaCustomError> {
+    let ain_env = get_app_env()?;
+    let collection_configs =
+        retrieve_collection_configs(ain_env.persist.clone(), ain_env.collections_db.clone())?;
+
+    for collection_config in collection_configs {
+        let name = collection_config.name.clone();
+        match load_vector_store(collection_config, config.upload_process_batch_size) {
+            Ok(vec_store) => {
+                ain_env.vector_store_map.insert(name.clone(), vec_store);
+                log::info!("Loaded collection `{}`", name);
+            }
+            Err(e) => log::error!("Failed to load collection `{}`: {}", name, e),
+        }
+    }
 
-    let metadata_db = denv
-        .create_db(Some("metadata"), DatabaseFlags::empty())
-        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    Ok(())
+}
+
+fn load_vector_store(
+    collection_config: CollectionConfig,
+    upload_process_batch_size: usize,
+) -> Result<Arc<VectorStore>, WaCustomError> {
+    let ain_env = get_app_env()?;
+    let collection_path = ain_env.collection_path(&collection_config.name);
+
+    let prop_file = Arc::new(
+        OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(collection_path.join("prop.data"))
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?,
+    );
 
-    let embeddings_db = denv
-        .create_db(Some("embeddings"), DatabaseFlags::empty())
-        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+    let index_file = OpenOptions::new()
+        .read(true)
+        .open(collection_path.join("0.index"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let root = load_root_node(index_file, &prop_file)?;
+
+    let lp = Arc::new(generate_tuples(
+        collection_config.hnsw_params.level_multiplier,
+        collection_config.max_cache_level,
+    ));
 
     let vec_store = Arc::new(VectorStore::new(
-        exec_queue_nodes,
-        max_cache_level,
-        name.clone(),
+        STM::new(Vec::new(), 1, true),
+        collection_config.max_cache_level,
+        collection_config.name.clone(),
+        collection_path.clone(),
         root,
         lp,
-        (size / 32) as usize,
+        collection_config.dimensions,
+        collection_config.dimensions / 32,
         prop_file,
-        MetaDb {
-            env: denv.clone(),
-            metadata_db: Arc::new(metadata_db.clone()),
-            embeddings_db: Arc::new(embeddings_db),
-        },
+        MetaDb::from_path(&collection_path)?,
         ArcShift::new(None),
-        Arc::new(QuantizationMetric::Scalar),
-        Arc::new(DistanceMetric::Cosine),
-        StorageType::UnsignedByte,
+        Arc::new(collection_config.quantization_metric),
+        Arc::new(collection_config.distance_metric),
+        collection_config.storage_type,
+        collection_config.payload_indexes,
+        collection_config.hnsw_params,
     ));
-    ain_env
+
+    let current_version = retrieve_current_version(vec_store.clone())?;
+    let branches = retrieve_branches(vec_store.clone())?;
+    *vec_store.version_hasher.lock().unwrap() = VersionHasher::from_branches(branches);
+    vec_store.set_current_version(Some(current_version));
+
+    // payload indexes are only kept in memory
+    for (key, metadata) in retrieve_all_vector_metadata(vec_store.clone())? {
+        vec_store
+            .payload_indexes
+            .write()
+            .unwrap()
+            .insert(&key, &metadata);
+    }
+
+    reindex_embeddings(vec_store.clone(), upload_process_batch_size)?;
+
+    // a transaction that was committed when the server went down is applied
+    // again, any other logged transaction is dropped
+    let wal = wal_path(&collection_path);
+    if let Some((transaction, operations)) = committed_transaction(read_wal(&wal)?) {
+        log::info!(
+            "Replaying committed transaction `{}` of collection `{}`",
+            transaction.hash,
+            collection_config.name
+        );
+        // the version may have been made visible before the server went down
+        if transaction.version > branch_version(&vec_store, &transaction.branch) {
+            vec_store.staged_operations.clone().update(operations);
+            vec_store
+                .current_open_transaction
+                .clone()
+                .update(Some(transaction.clone()));
+            let version = transaction.version;
+            let transaction_branch = transaction.branch.clone();
+            if let Err(e) =
+                commit_transaction(vec_store.clone(), transaction, upload_process_batch_size)
+            {
+                // only indexing it failed, which the background worker retries
+                if branch_version(&vec_store, &transaction_branch) < version {
+                    return Err(e);
+                }
+                log::error!(
+                    "Failed to index replayed version {} of `{}`: {}",
+                    version,
+                    collection_config.name,
+                    e
+                );
+            }
+        }
+    }
+    clear_wal(&wal)?;
+
+    Ok(vec_store)
+}
+
+// The version at the head of the branch, 0 if there's no such branch
+fn branch_version(vec_store: &VectorStore, branch: &str) -> u32 {
+    vec_store
+        .version_hasher
+        .lock()
+        .unwrap()
+        .branch(branch)
+        .map_or(0, |branch_info| branch_info.current_version)
+}
+
+// Loads the topmost node of the root chain, which is always the first node
+// written to `0.index`, and walks down the `child` links to the level 0 root,
+// resolving the props of the chain along the way. The `parent` links are
+// loaded as separate copies of the nodes above, so they're pointed back at the
+// nodes of the chain for searches to go up and down the same nodes
+fn load_root_node(
+    index_file: File,
+    prop_file: &File,
+) -> Result<LazyItemRef<MergedNode>, WaCustomError> {
+    let cache = Arc::new(NodeRegistry::new(1000, index_file));
+    let file_index = FileIndex::Valid {
+        offset: FileOffset(0),
+        version: VersionId(0),
+    };
+
+    let mut item: LazyItem<MergedNode> = cache
+        .load_item(file_index)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    let mut parent: Option<LazyItem<MergedNode>> = None;
+    loop {
+        let mut node_arc = item.get_data().ok_or(WaCustomError::LazyLoadingError(
+            "Root chain node is not loaded".to_string(),
+        ))?;
+        let node = node_arc.get();
+
+        if let PropState::Pending(location) = node.get_prop() {
+            let prop = read_prop_from_file(location, prop_file)?;
+            node.set_prop_ready(Arc::new(prop));
+        }
+
+        if let Some(parent) = parent {
+            node.parent.item.clone().update(parent);
+        }
+
+        if node.hnsw_level.0 == 0 {
+            return Ok(LazyItemRef::from_lazy(item));
+        }
+
+        let child = node.child.item.clone().get().clone();
+        parent = Some(item);
+        item = child;
+    }
+}
+
+pub fn list_collections() -> Result<Vec<CollectionInfo>, WaCustomError> {
+    let ain_env = get_app_env()?;
+    let collection_configs =
+        retrieve_collection_configs(ain_env.persist.clone(), ain_env.collections_db.clone())?;
+
+    collection_configs
+        .into_iter()
+        .filter_map(|collection_config| {
+            let vec_store = ain_env
+                .vector_store_map
+                .get(&collection_config.name)?
+                .clone();
+            Some(collection_info(collection_config, vec_store))
+        })
+        .collect()
+}
+
+pub fn get_collection(name: &str) -> Result<CollectionInfo, WaCustomError> {
+    let ain_env = get_app_env()?;
+    let vec_store = ain_env
         .vector_store_map
-        .insert(name.clone(), vec_store.clone());
+        .get(name)
+        .ok_or(WaCustomError::NotFound(format!("collection `{}`", name)))?
+        .clone();
+    let collection_config =
+        retrieve_collection_configs(ain_env.persist.clone(), ain_env.collections_db.clone())?
+            .into_iter()
+            .find(|collection_config| collection_config.name == name)
+            .ok_or(WaCustomError::NotFound(format!("collection `{}`", name)))?;
 
-    let result = store_current_version(vec_store.clone(), "main".to_string(), 0);
-    let version_hash = result.expect("Failed to get VersionHash");
-    vec_store.set_current_version(Some(version_hash));
+    collection_info(collection_config, vec_store)
+}
+
+fn collection_info(
+    collection_config: CollectionConfig,
+    vec_store: Arc<VectorStore>,
+) -> Result<CollectionInfo, WaCustomError> {
+    Ok(CollectionInfo {
+        name: collection_config.name,
+        dimensions: collection_config.dimensions,
+        max_cache_level: collection_config.max_cache_level,
+        quantization_metric: collection_config.quantization_metric,
+        distance_metric: collection_config.distance_metric,
+        storage_type: collection_config.storage_type,
+        payload_indexes: collection_config.payload_indexes,
+        hnsw_params: collection_config.hnsw_params,
+        count_indexed: retrieve_counter(vec_store.clone(), "count_indexed")?,
+        count_unindexed: retrieve_counter(vec_store.clone(), "count_unindexed")?,
+        current_version: vec_store.get_current_version(),
+    })
+}
+
+// Aborts the open transaction of a collection that's about to be deleted, so
+// that it releases the write lock, and stops its background indexing and index
+// build, so that they don't bring it back once it's deleted
+pub fn stop_collection_writes(vec_store: &VectorStore) -> Result<(), WaCustomError> {
+    let open_transaction = vec_store.current_open_transaction.clone().get().clone();
+    if let Some(transaction) = open_transaction {
+        match abort_transaction(vec_store, &transaction.hash) {
+            // committed or aborted in the meantime
+            Ok(()) | Err(WaCustomError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut indexing = vec_store.indexing.lock().unwrap();
+    // the worker stops after its current run
+    indexing.pending = false;
+    indexing.cancel_build = true;
 
     Ok(())
 }
 
+// Drops the collection from the registry and removes its data directory,
+// which also holds the collection's LMDB environment. The directory is moved
+// aside first, so that the collection is left as it was if its config can't
+// be deleted. `permit` is the collection's write lock, and `index_lock` is
+// held too, so that no write or index build is still going on
+pub fn delete_vector_store(name: &str, permit: OwnedSemaphorePermit) -> Result<(), WaCustomError> {
+    let ain_env = get_app_env()?;
+    let vec_store = ain_env
+        .vector_store_map
+        .get(name)
+        .ok_or(WaCustomError::NotFound(format!("collection `{}`", name)))?
+        .clone();
+    let _index_guard = vec_store.index_lock.lock().unwrap();
+
+    let collection_path = &vec_store.collection_path;
+    let deleted_path = collection_path.with_extension("deleted");
+    // left over by a deletion that didn't finish
+    let _ = remove_dir_all(&deleted_path);
+    rename(collection_path, &deleted_path).map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    if let Err(e) = delete_collection_config(
+        ain_env.persist.clone(),
+        ain_env.collections_db.clone(),
+        name,
+    ) {
+        rename(&deleted_path, collection_path)
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+        return Err(e);
+    }
+
+    ain_env.vector_store_map.remove(name);
+    drop(permit);
+
+    if let Err(e) = remove_dir_all(&deleted_path) {
+        log::warn!(
+            "Failed to remove the data of deleted collection `{}`: {}",
+            name,
+            e
+        );
+    }
+
+    Ok(())
+}
+
+// Waits for the collection's write lock, giving up after `timeout`
+pub async fn acquire_write_lock(
+    vec_store: &VectorStore,
+    timeout: Duration,
+) -> Result<OwnedSemaphorePermit, WaCustomError> {
+    match tokio::time::timeout(timeout, vec_store.write_lock.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(permit),
+        Ok(Err(e)) => Err(WaCustomError::LockError(e.to_string())),
+        Err(_) => Err(WaCustomError::LockError(
+            "Timed out waiting for the ongoing upsert or transaction to finish".to_string(),
+        )),
+    }
+}
+
+// Opens a transaction as the next version of `branch`, which holds the write
+// lock until it's committed or aborted
+pub fn open_transaction(
+    vec_store: &VectorStore,
+    branch: &str,
+    permit: OwnedSemaphorePermit,
+) -> Result<VersionHash, WaCustomError> {
+    // the branch head only moves when the transaction is committed
+    let mut hasher = vec_store.version_hasher.lock().unwrap().clone();
+    let new_ver = hasher
+        .branch(branch)
+        .ok_or_else(|| WaCustomError::NotFound(format!("branch {}", branch)))?
+        .current_version
+        + 1;
+    let hash = hasher.generate_hash(branch, new_ver, None, None);
+
+    let begin = WalRecord::Begin {
+        transaction: hash.clone(),
+    };
+    append_wal_records(&wal_path(&vec_store.collection_path), &[begin], false)?;
+
+    vec_store.staged_operations.clone().update(Vec::new());
+    *vec_store.transaction_permit.lock().unwrap() = Some(permit);
+    *vec_store.transaction_activity.lock().unwrap() = Instant::now();
+    vec_store
+        .current_open_transaction
+        .clone()
+        .update(Some(hash.clone()));
+
+    Ok(hash)
+}
+
+// Deletes the vectors that exist in the collection and returns how many were
+// deleted. Like implicit upserts, it creates a new version of "main" unless
+// there's nothing to delete
+pub fn run_delete(
+    vec_store: Arc<VectorStore>,
+    vector_ids: Vec<VectorId>,
+) -> Result<u32, WaCustomError> {
+    let mut operations = Vec::new();
+    for id in vector_ids {
+        if embedding_exists(vec_store.clone(), &id)? {
+            operations.push(StagedOperation::Delete { id });
+        }
+    }
+    if operations.is_empty() {
+        return Ok(0);
+    }
+
+    let (_, counts) = apply_operations(
+        vec_store.clone(),
+        "main",
+        next_version(&vec_store)?,
+        operations,
+    )?;
+
+    Ok(counts.deleted)
+}
+
+// Upserts the vectors as a new version of "main", so that a version never
+// changes once it's created. The embeddings are indexed by the collection's
+// background worker once enough of them are waiting
 pub fn run_upload(
     vec_store: Arc<VectorStore>,
-    vecxx: Vec<(VectorIdValue, Vec<f32>)>,
+    vecxx: Vec<(VectorIdValue, Vec<f32>, Option<Metadata>)>,
     config: web::Data<Config>,
-) -> () {
-    vecxx.into_par_iter().for_each(|(id, vec)| {
-        let hash_vec = convert_value(id);
-        let storage = vec_store
-            .quantization_metric
-            .quantize(&vec, vec_store.storage_type);
-        let vec_emb = VectorEmbedding {
-            raw_vec: Arc::new(storage),
-            hash_vec,
-        };
+) -> Result<OperationCounts, WaCustomError> {
+    if vecxx.is_empty() {
+        return Ok(OperationCounts::default());
+    }
+
+    let operations = vecxx
+        .into_iter()
+        .map(|(id, values, metadata)| StagedOperation::Upsert {
+            id: convert_value(id),
+            values,
+            metadata,
+        })
+        .collect();
+    let (_, counts) = apply_operations(
+        vec_store.clone(),
+        "main",
+        next_version(&vec_store)?,
+        operations,
+    )?;
+
+    let (_, count_unindexed) = indexing_counts(vec_store.clone())?;
+    if count_unindexed >= config.upload_threshold {
+        start_indexing(vec_store, config.upload_process_batch_size);
+    }
+
+    Ok(counts)
+}
+
+fn next_version(vec_store: &VectorStore) -> Result<u32, WaCustomError> {
+    vec_store
+        .get_current_version()
+        .map(|version| version.version + 1)
+        .ok_or_else(|| WaCustomError::NotFound("current version".to_string()))
+}
 
-        insert_embedding(vec_store.clone(), &vec_emb).expect("Failed to inert embedding to LMDB");
+// Indexes the collection's unindexed embeddings on a background thread. If the
+// worker is already running, it does another run once the current one is done
+pub fn start_indexing(vec_store: Arc<VectorStore>, upload_process_batch_size: usize) {
+    {
+        let mut indexing = vec_store.indexing.lock().unwrap();
+        indexing.pending = true;
+        if indexing.running {
+            return;
+        }
+        indexing.running = true;
+    }
+
+    thread::spawn(move || {
+        let _worker = IndexingWorker(vec_store.indexing.clone());
+        loop {
+            {
+                let mut indexing = vec_store.indexing.lock().unwrap();
+                if !indexing.pending {
+                    indexing.running = false;
+                    break;
+                }
+                indexing.pending = false;
+            }
+
+            let start = Instant::now();
+            let result = vec_store
+                .get_current_version()
+                .ok_or_else(|| WaCustomError::NotFound("current version".to_string()))
+                .and_then(|version| {
+                    index_new_embeddings(&vec_store, version.version + 1, upload_process_batch_size)
+                });
+
+            match result {
+                Ok(indexed) => {
+                    let elapsed = start.elapsed().as_secs_f64();
+                    if indexed > 0 && elapsed > 0.0 {
+                        vec_store.indexing.lock().unwrap().throughput = indexed as f64 / elapsed;
+                    }
+                }
+                Err(e) => eprintln!(
+                    "Failed to index embeddings of {}: {}",
+                    vec_store.database_name, e
+                ),
+            }
+        }
     });
+}
 
-    let env = vec_store.lmdb.env.clone();
-    let metadata_db = vec_store.lmdb.metadata_db.clone();
+// Clears `running` if the indexing worker panics, so that the next upsert
+// starts a new one
+struct IndexingWorker(Arc<Mutex<IndexingState>>);
 
-    let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+impl Drop for IndexingWorker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let mut indexing = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            indexing.running = false;
+        }
+    }
+}
+
+// Indexes the collection's unindexed embeddings into its current graph, which
+// may have been rebuilt since `vec_store` was looked up, and writes the nodes
+// to `{version}.index`. Returns how many embeddings were indexed. While a new
+// graph is being built they're left unindexed, the build indexes them into the
+// new graph once it's swapped in
+fn index_new_embeddings(
+    vec_store: &Arc<VectorStore>,
+    version: u32,
+    upload_process_batch_size: usize,
+) -> Result<u32, WaCustomError> {
+    if build_running(vec_store) {
+        return Ok(0);
+    }
+    let _index_guard = vec_store.index_lock.lock().unwrap();
+    let vec_store = current_store(vec_store);
+    let indexed = index_embeddings(vec_store.clone(), upload_process_batch_size)?;
+    persist_new_nodes(vec_store, version)?;
+    Ok(indexed)
+}
 
-    let count_unindexed = txn
-        .get(*metadata_db, &"count_unindexed")
-        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))
-        .and_then(|bytes| {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            Ok(u32::from_le_bytes(bytes))
+// The store the collection's requests currently go through, `vec_store` if the
+// collection isn't registered
+fn current_store(vec_store: &Arc<VectorStore>) -> Arc<VectorStore> {
+    get_app_env()
+        .ok()
+        .and_then(|env| {
+            env.vector_store_map
+                .get(&vec_store.database_name)
+                .map(|store| store.clone())
         })
-        .expect("Failed to retrieve `count_unindexed`");
+        .unwrap_or_else(|| vec_store.clone())
+}
+
+// Starts building a new graph for the collection on a background thread, from
+// the vectors stored in `vec_raw.0` when the build starts. Searches and writes
+// go on while it runs, the embeddings stored in the meantime are left
+// unindexed, where searches still find them, until the background indexing
+// adds them to the new graph
+pub fn start_index_build(
+    vec_store: Arc<VectorStore>,
+    params: CreateIndex,
+    upload_process_batch_size: usize,
+) -> Result<IndexBuild, WaCustomError> {
+    let max_cache_level = params.max_cache_level.unwrap_or(vec_store.max_cache_level);
+    let hnsw_params = params.hnsw_params.unwrap_or(vec_store.hnsw_params);
+    if !hnsw_params.is_valid() || max_cache_level > MAX_CACHE_LEVEL {
+        return Err(WaCustomError::InvalidParams);
+    }
+    let build = IndexBuild {
+        state: IndexBuildState::Running,
+        max_cache_level,
+        hnsw_params,
+        indexed: 0,
+        total: embeddings_count(vec_store.clone())?,
+        error: None,
+    };
 
-    txn.abort();
+    {
+        let mut indexing = vec_store.indexing.lock().unwrap();
+        if indexing
+            .build
+            .as_ref()
+            .map_or(false, |build| build.state == IndexBuildState::Running)
+        {
+            return Err(WaCustomError::LockError(
+                "An index build is already running".to_string(),
+            ));
+        }
+        indexing.build = Some(build.clone());
+        indexing.cancel_build = false;
+    }
 
-    if count_unindexed >= config.upload_threshold {
-        index_embeddings(vec_store.clone(), config.upload_process_batch_size)
-            .expect("Failed to index embeddings");
+    thread::spawn(move || {
+        let result = build_index_graph(
+            vec_store.clone(),
+            max_cache_level,
+            hnsw_params,
+            upload_process_batch_size,
+        );
+
+        let swapped = matches!(result, Ok(true));
+        {
+            let mut indexing = vec_store.indexing.lock().unwrap();
+            if let Some(build) = indexing.build.as_mut() {
+                match result {
+                    Ok(true) => build.state = IndexBuildState::Completed,
+                    Ok(false) => build.state = IndexBuildState::Cancelled,
+                    Err(e) => {
+                        build.state = IndexBuildState::Failed;
+                        build.error = Some(e.to_string());
+                    }
+                }
+            }
+        }
+
+        // the embeddings stored during the build are indexed into the new graph
+        if swapped {
+            start_indexing(vec_store, upload_process_batch_size);
+        }
+    });
+
+    Ok(build)
+}
+
+// Builds the new graph on a copy of the store, which shares everything but
+// the graph with the current one. Returns false if the build was cancelled
+fn build_index_graph(
+    vec_store: Arc<VectorStore>,
+    max_cache_level: u8,
+    hnsw_params: HnswParams,
+    upload_process_batch_size: usize,
+) -> Result<bool, WaCustomError> {
+    let (new_store, root_path) =
+        new_graph_store(&vec_store, "0.index.build", max_cache_level, hnsw_params)?;
+
+    let result = build_index(
+        new_store.clone(),
+        upload_process_batch_size,
+        |vector_id| embedding_offset(vec_store.clone(), vector_id),
+        |indexed| {
+            let mut indexing = vec_store.indexing.lock().unwrap();
+            if let Some(build) = indexing.build.as_mut() {
+                build.indexed = indexed;
+            }
+            !indexing.cancel_build
+        },
+    )
+    .and_then(|built| match built {
+        Some(built) => swap_built_graph(&vec_store, &new_store, &root_path, built),
+        None => Ok(false),
+    });
+    if !matches!(result, Ok(true)) {
+        let _ = remove_file(&root_path);
+    }
+
+    result
+}
+
+// Swaps the built graph in, once the writes that are going on are done and
+// with new writes waiting, so that none of them is missed. Vectors that were
+// replaced or deleted since they were indexed are unlinked from the new graph,
+// and the embeddings stored after the ones it was built from are left
+// unindexed. Returns false if the build was cancelled in the meantime
+fn swap_built_graph(
+    vec_store: &Arc<VectorStore>,
+    new_store: &Arc<VectorStore>,
+    root_path: &PathBuf,
+    (count_indexed, next_file_offset, offsets): (u32, u32, HashMap<VectorId, u32>),
+) -> Result<bool, WaCustomError> {
+    let _index_guard = vec_store.index_lock.lock().unwrap();
+    let _append_guard = vec_store.append_lock.lock().unwrap();
+
+    if vec_store.indexing.lock().unwrap().cancel_build {
+        return Ok(false);
+    }
+    // a rollback swaps in a graph of its own, and a deleted collection must
+    // not be brought back
+    let ain_env = get_app_env()?;
+    match ain_env.vector_store_map.get(&vec_store.database_name) {
+        Some(current) if Arc::ptr_eq(&current, vec_store) => {}
+        _ => {
+            return Err(WaCustomError::LockError(
+                "The collection was rolled back or deleted during the build".to_string(),
+            ))
+        }
+    }
+
+    for (vector_id, offset) in &offsets {
+        if embedding_offset(vec_store.clone(), vector_id)? != Some(*offset) {
+            remove_vector_nodes(new_store.clone(), vector_id)?;
+        }
     }
 
-    // Update version
-    let ver = vec_store
+    let version = vec_store
         .get_current_version()
-        .expect("No current version found");
-    let new_ver = ver.version + 1;
+        .ok_or_else(|| WaCustomError::NotFound("current version".to_string()))?;
+    persist_new_nodes(new_store.clone(), version.version + 1)?;
 
-    // Create new version file
-    let ver_file = Rc::new(RefCell::new(
+    let mut collection_config =
+        retrieve_collection_configs(ain_env.persist.clone(), ain_env.collections_db.clone())?
+            .into_iter()
+            .find(|config| config.name == vec_store.database_name)
+            .ok_or_else(|| WaCustomError::NotFound("collection".to_string()))?;
+    collection_config.max_cache_level = new_store.max_cache_level;
+    collection_config.hnsw_params = new_store.hnsw_params;
+    store_collection_config(
+        ain_env.persist.clone(),
+        ain_env.collections_db.clone(),
+        &collection_config,
+    )?;
+    rename(root_path, vec_store.collection_path.join("0.index"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    swap_graph_store(vec_store, new_store)?;
+
+    let count_unindexed = count_embeddings_from(vec_store, next_file_offset)?;
+    store_indexing_offsets(
+        vec_store.clone(),
+        count_indexed,
+        count_unindexed,
+        next_file_offset,
+    )?;
+
+    Ok(true)
+}
+
+// A copy of the store with an empty graph, which shares everything else with
+// the current one. The graph's root chain is written to `file_name`, which
+// replaces `0.index` once the graph is swapped in
+fn new_graph_store(
+    vec_store: &VectorStore,
+    file_name: &str,
+    max_cache_level: u8,
+    hnsw_params: HnswParams,
+) -> Result<(Arc<VectorStore>, PathBuf), WaCustomError> {
+    let root_prop = match vec_store.root_vec.item.clone().get().get_data() {
+        Some(mut root) => match root.get().get_prop() {
+            PropState::Ready(prop) => prop,
+            PropState::Pending(_) => {
+                return Err(WaCustomError::NodeError(
+                    "Root node prop is not loaded".to_string(),
+                ))
+            }
+        },
+        None => {
+            return Err(WaCustomError::NodeError(
+                "Root node is not loaded".to_string(),
+            ))
+        }
+    };
+
+    let root_path = vec_store.collection_path.join(file_name);
+    let root_file = Rc::new(RefCell::new(
         OpenOptions::new()
             .create(true)
-            .append(true)
-            .open(format!("{}.index", new_ver))
-            .map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to open new version file: {}", e))
-            })
-            .unwrap(),
+            .write(true)
+            .truncate(true)
+            .open(&root_path)
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?,
     ));
-
     let mut writer =
-        CustomBufferedWriter::new(ver_file.clone()).expect("Failed opening custom buffer");
+        CustomBufferedWriter::new(root_file).map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    let root = create_root_chain(root_prop, max_cache_level, &mut writer);
+    writer
+        .flush()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
 
-    println!("run_upload 333");
-    match auto_commit_transaction(vec_store.clone(), &mut writer) {
-        Ok(_) => (),
-        Err(e) => {
-            eprintln!("Failed node persist(nbr1): {}", e);
-        }
-    };
+    let mut new_store = vec_store.clone();
+    new_store.root_vec = root;
+    new_store.max_cache_level = max_cache_level;
+    new_store.levels_prob = Arc::new(generate_tuples(
+        hnsw_params.level_multiplier,
+        max_cache_level,
+    ));
+    new_store.hnsw_params = hnsw_params;
+    new_store.vector_nodes = Arc::new(RwLock::new(HashMap::new()));
+    new_store.exec_queue_nodes = STM::new(Vec::new(), 1, true);
+
+    Ok((Arc::new(new_store), root_path))
 }
 
+// Makes the graph of `new_store` the one the collection's requests go through
+fn swap_graph_store(vec_store: &VectorStore, new_store: &VectorStore) -> Result<(), WaCustomError> {
+    let ain_env = get_app_env()?;
+
+    // requests that got hold of the current store before the swap keep
+    // unlinking replaced vectors from the node map, so it's kept shared
+    let nodes = std::mem::take(&mut *new_store.vector_nodes.write().unwrap());
+    *vec_store.vector_nodes.write().unwrap() = nodes;
+
+    let mut swapped = new_store.clone();
+    swapped.vector_nodes = vec_store.vector_nodes.clone();
+    swapped.exec_queue_nodes = vec_store.exec_queue_nodes.clone();
+    ain_env
+        .vector_store_map
+        .insert(vec_store.database_name.clone(), Arc::new(swapped));
+
+    Ok(())
+}
+
+fn build_running(vec_store: &VectorStore) -> bool {
+    vec_store
+        .indexing
+        .lock()
+        .unwrap()
+        .build
+        .as_ref()
+        .map_or(false, |build| build.state == IndexBuildState::Running)
+}
+
+pub fn index_build(vec_store: Arc<VectorStore>) -> Option<IndexBuild> {
+    vec_store.indexing.lock().unwrap().build.clone()
+}
+
+// Asks the running index build to stop, it's stopped after its current batch
+pub fn cancel_index_build(vec_store: Arc<VectorStore>) -> Result<IndexBuild, WaCustomError> {
+    let mut indexing = vec_store.indexing.lock().unwrap();
+    let build = indexing
+        .build
+        .clone()
+        .ok_or_else(|| WaCustomError::NotFound("index build".to_string()))?;
+    if build.state != IndexBuildState::Running {
+        return Err(WaCustomError::InvalidParams);
+    }
+    indexing.cancel_build = true;
+
+    Ok(build)
+}
+
+pub fn index_status(vec_store: Arc<VectorStore>) -> Result<IndexStatus, WaCustomError> {
+    let (count_indexed, count_unindexed) = indexing_counts(vec_store.clone())?;
+    let indexing = vec_store.indexing.lock().unwrap();
+
+    Ok(IndexStatus {
+        count_indexed,
+        count_unindexed,
+        indexing: indexing.running,
+        throughput: indexing.throughput,
+    })
+}
+
+// Filtered searches with at most this many candidate vectors skip the graph
+// and are answered by an exact scan
+const EXACT_SCAN_THRESHOLD: usize = 1000;
+
+// Search hits, most similar first, along with their metadata
+pub type SearchResults = Vec<(VectorId, MetricResult, Option<Metadata>)>;
+
 pub async fn ann_vector_query(
     vec_store: Arc<VectorStore>,
     query: Vec<f32>,
-) -> Result<Option<Vec<(VectorId, MetricResult)>>, WaCustomError> {
+    k: usize,
+    ef_search: Option<usize>,
+    filter: Option<&Filter>,
+    branch: &str,
+    version: Option<u32>,
+) -> Result<Option<SearchResults>, WaCustomError> {
     let vector_store = vec_store.clone();
     let vec_hash = VectorId::Str("query".to_string());
-    let root = &vector_store.root_vec;
     let vector_list = vector_store
         .quantization_metric
         .quantize(&query, vector_store.storage_type);
@@ -255,13 +1076,95 @@ pub async fn ann_vector_query(
         hash_vec: vec_hash.clone(),
     };
 
+    // past versions and other branches aren't in the graph, the vectors they
+    // had are compared against the query one by one
+    if let Some(version) = past_version(&vec_store, branch, version)? {
+        let snapshot = retrieve_branch_vectors_as_of(vec_store.clone(), branch, version)?;
+        let results = snapshot_scan(vec_store, &vec_emb, &snapshot, filter, k)?;
+        return Ok(Some(results));
+    }
+
+    let search_filter = filter.map(|filter| SearchFilter {
+        filter,
+        candidates: vec_store.payload_indexes.read().unwrap().candidates(filter),
+    });
+
+    // when the payload indexes narrow the filter down to a few vectors, it's
+    // cheaper to compare the query against all of them
+    if let Some(search_filter) = &search_filter {
+        if let Some(candidates) = &search_filter.candidates {
+            if candidates.len() <= EXACT_SCAN_THRESHOLD {
+                let results = filtered_scan(vec_store.clone(), &vec_emb, search_filter, k)?;
+                return attach_metadata(vec_store, Some(results));
+            }
+        }
+    }
+
     let results = ann_search(
         vec_store.clone(),
-        vec_emb,
-        root.item.clone().get().clone(),
-        vec_store.max_cache_level.try_into().unwrap(),
+        vec_emb.clone(),
+        k,
+        ef_search.unwrap_or(vec_store.hnsw_params.ef_search),
+        search_filter.as_ref(),
     )?;
-    let output = remove_duplicates_and_filter(results);
+    let mut output = remove_duplicates_and_filter(results, k);
+
+    if let Some(search_filter) = &search_filter {
+        if output.as_ref().map_or(0, |results| results.len()) < k {
+            output = Some(filtered_scan(
+                vec_store.clone(),
+                &vec_emb,
+                search_filter,
+                k,
+            )?);
+        }
+    }
+
+    // vectors that weren't indexed yet aren't in the graph
+    let unindexed = unindexed_scan(vec_store.clone(), &vec_emb, filter, k)?;
+    if !unindexed.is_empty() {
+        output = Some(merge_results(output.unwrap_or_default(), unindexed, k));
+    }
+
+    attach_metadata(vec_store, output)
+}
+
+// Merges two lists of results into the `k` most similar, keeping a vector
+// that's in both lists only once
+fn merge_results(
+    a: Vec<(VectorId, MetricResult)>,
+    b: Vec<(VectorId, MetricResult)>,
+    k: usize,
+) -> Vec<(VectorId, MetricResult)> {
+    let mut results: Vec<_> = a.into_iter().chain(b).collect();
+    results.sort_by(|a, b| {
+        b.1.get_similarity()
+            .partial_cmp(&a.1.get_similarity())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut seen = HashSet::new();
+    results.retain(|(id, _)| seen.insert(id.clone()));
+    results.truncate(k);
+    results
+}
+
+fn attach_metadata(
+    vec_store: Arc<VectorStore>,
+    results: Option<Vec<(VectorId, MetricResult)>>,
+) -> Result<Option<SearchResults>, WaCustomError> {
+    let output = match results {
+        Some(results) => Some(
+            results
+                .into_iter()
+                .map(|(id, score)| {
+                    let metadata = retrieve_vector_metadata(vec_store.clone(), &id)?;
+                    Ok((id, score, metadata))
+                })
+                .collect::<Result<Vec<_>, WaCustomError>>()?,
+        ),
+        None => None,
+    };
     Ok(output)
 }
 
@@ -273,6 +1176,664 @@ pub async fn fetch_vector_neighbors(
     return results.expect("Failed fetching vector neighbors");
 }
 
+// Adds operations to the open transaction, they only become visible once the
+// transaction is committed. Updates and deletes must target a vector that
+// exists, either in the store or as a result of the operations staged before,
+// and upserted or updated vectors must have the collection's dimensions
+pub fn stage_operations(
+    vec_store: Arc<VectorStore>,
+    transaction_id: &str,
+    operations: Vec<StagedOperation>,
+) -> Result<(), WaCustomError> {
+    let _staging_guard = vec_store.staging_lock.lock().unwrap();
+
+    // the transaction may have been committed or aborted since the request
+    // looked it up
+    let mut cot_arc = vec_store.current_open_transaction.clone();
+    let transaction = match cot_arc.get() {
+        Some(transaction) if transaction.hash == transaction_id => transaction.clone(),
+        _ => return Err(WaCustomError::NotFound("transaction".to_string())),
+    };
+
+    let mut staged_arc = vec_store.staged_operations.clone();
+    let staged = staged_arc.get().clone();
+
+    for (i, operation) in operations.iter().enumerate() {
+        let vector_id = operation.vector_id();
+        match operation {
+            StagedOperation::Upsert { values, .. } | StagedOperation::Update { values, .. }
+                if values.len() != vec_store.dimensions =>
+            {
+                return Err(WaCustomError::InvalidParams);
+            }
+            _ => {}
+        }
+
+        let exists = match staged
+            .iter()
+            .chain(&operations[..i])
+            .rev()
+            .find(|op| op.vector_id() == vector_id)
+        {
+            Some(StagedOperation::Delete { .. }) => false,
+            Some(_) => true,
+            None => exists_in_head(vec_store.clone(), &transaction, vector_id)?,
+        };
+        match operation {
+            StagedOperation::Update { .. } | StagedOperation::Delete { .. } if !exists => {
+                return Err(WaCustomError::NotFound(format!("vector {}", vector_id)));
+            }
+            _ => {}
+        }
+    }
+
+    let records: Vec<WalRecord> = operations
+        .iter()
+        .map(|operation| WalRecord::Operation {
+            operation: operation.clone(),
+        })
+        .collect();
+    append_wal_records(&wal_path(&vec_store.collection_path), &records, false)?;
+
+    let mut staged = staged;
+    staged.extend(operations);
+    staged_arc.update(staged);
+    *vec_store.transaction_activity.lock().unwrap() = Instant::now();
+
+    Ok(())
+}
+
+// Drops the open transaction along with its staged operations and releases
+// the collection's write lock
+pub fn abort_transaction(
+    vec_store: &VectorStore,
+    transaction_id: &str,
+) -> Result<(), WaCustomError> {
+    let _staging_guard = vec_store.staging_lock.lock().unwrap();
+
+    let mut cot_arc = vec_store.current_open_transaction.clone();
+    if cot_arc
+        .get()
+        .as_ref()
+        .map(|transaction| transaction.hash.as_str())
+        != Some(transaction_id)
+    {
+        return Err(WaCustomError::NotFound("transaction".to_string()));
+    }
+
+    // without a commit record the logged transaction would be discarded on
+    // startup anyway
+    clear_wal(&wal_path(&vec_store.collection_path))?;
+
+    cot_arc.update(None);
+    vec_store.staged_operations.clone().update(Vec::new());
+    vec_store.transaction_permit.lock().unwrap().take();
+
+    Ok(())
+}
+
+// Aborts the transaction once nothing was staged in it for `idle_timeout`, so
+// that a client that went away doesn't hold the collection's write lock
+// forever. Returns once the transaction is committed or aborted
+pub async fn expire_idle_transaction(
+    vec_store: Arc<VectorStore>,
+    transaction_id: String,
+    idle_timeout: Duration,
+) {
+    loop {
+        let idle = vec_store.transaction_activity.lock().unwrap().elapsed();
+        if idle < idle_timeout {
+            tokio::time::sleep(idle_timeout - idle).await;
+            continue;
+        }
+
+        let store = vec_store.clone();
+        let id = transaction_id.clone();
+        match web::block(move || abort_transaction(&store, &id)).await {
+            Ok(Ok(())) => {
+                log::info!(
+                    "Aborted transaction {} of `{}` after it was idle for {:?}",
+                    transaction_id,
+                    vec_store.database_name,
+                    idle
+                );
+                return;
+            }
+            // committed or aborted by the client
+            Ok(Err(WaCustomError::NotFound(_))) => return,
+            Ok(Err(e)) => log::error!("Failed to abort transaction {}: {}", transaction_id, e),
+            Err(e) => log::error!("Failed to abort transaction {}: {}", transaction_id, e),
+        }
+        // retried after another timeout
+        tokio::time::sleep(idle_timeout).await;
+    }
+}
+
+// Applies the operations staged in the open transaction as the next version
+// of its branch, then indexes them if the branch is "main". The commit record in the WAL is what makes the
+// transaction replayed on startup, so it's dropped again if the operations
+// couldn't be applied, leaving the transaction open as it was. Once they're
+// applied the transaction is closed and its write lock released, even if
+// indexing them fails
+pub fn commit_transaction(
+    vec_store: Arc<VectorStore>,
+    transaction: VersionHash,
+    upload_process_batch_size: usize,
+) -> Result<(), WaCustomError> {
+    let staging_guard = vec_store.staging_lock.lock().unwrap();
+
+    // the transaction may have been aborted since the request looked it up
+    if vec_store
+        .current_open_transaction
+        .clone()
+        .get()
+        .as_ref()
+        .map(|open| &open.hash)
+        != Some(&transaction.hash)
+    {
+        return Err(WaCustomError::NotFound("transaction".to_string()));
+    }
+
+    let wal = wal_path(&vec_store.collection_path);
+    let wal_len = wal_len(&wal)?;
+    append_wal_records(&wal, &[WalRecord::Commit], true)?;
+
+    let operations = vec_store.staged_operations.clone().get().clone();
+
+    // only the last operation staged for a vector has to be applied
+    let mut seen = HashSet::new();
+    let mut operations: Vec<StagedOperation> = operations
+        .into_iter()
+        .rev()
+        .filter(|op| seen.insert(op.vector_id().clone()))
+        .collect();
+    operations.reverse();
+
+    if let Err(e) = apply_operations(
+        vec_store.clone(),
+        &transaction.branch,
+        transaction.version,
+        operations,
+    ) {
+        truncate_wal(&wal, wal_len)?;
+        return Err(e);
+    }
+
+    vec_store.staged_operations.clone().update(Vec::new());
+    vec_store.current_open_transaction.clone().update(None);
+
+    // the version is already visible, if the log can't be cleared the replay
+    // on startup finds it applied and skips it
+    if let Err(e) = clear_wal(&wal) {
+        log::error!(
+            "Failed to clear the WAL of `{}`: {}",
+            vec_store.database_name,
+            e
+        );
+    }
+    vec_store.transaction_permit.lock().unwrap().take();
+    drop(staging_guard);
+
+    // only "main" is in the graph
+    if transaction.branch != "main" {
+        return Ok(());
+    }
+    index_version(vec_store, transaction.version, upload_process_batch_size)
+}
+
+// Applies the operations as version `version` of `branch` and moves the branch
+// head to it. The embeddings are appended first, where nothing refers to
+// them yet, then the version becomes visible with the single LMDB transaction
+// that stores their offsets. If anything fails before that, the collection is
+// left as it was
+fn apply_operations(
+    vec_store: Arc<VectorStore>,
+    branch: &str,
+    version: u32,
+    operations: Vec<StagedOperation>,
+) -> Result<(VersionHash, OperationCounts), WaCustomError> {
+    let embeddings: Vec<Option<VectorEmbedding>> = operations
+        .par_iter()
+        .map(|operation| match operation {
+            StagedOperation::Upsert { id, values, .. }
+            | StagedOperation::Update { id, values, .. } => Some(VectorEmbedding {
+                raw_vec: Arc::new(
+                    vec_store
+                        .quantization_metric
+                        .quantize(values, vec_store.storage_type),
+                ),
+                hash_vec: id.clone(),
+            }),
+            StagedOperation::Delete { .. } => None,
+        })
+        .collect();
+
+    let _append_guard = vec_store.append_lock.lock().unwrap();
+
+    let mut writes = Vec::with_capacity(operations.len());
+    for (operation, embedding) in operations.into_iter().zip(embeddings) {
+        match (operation, embedding) {
+            (
+                StagedOperation::Upsert {
+                    id,
+                    values,
+                    metadata,
+                }
+                | StagedOperation::Update {
+                    id,
+                    values,
+                    metadata,
+                },
+                Some(embedding),
+            ) => {
+                let (offset, values_offset) =
+                    append_embedding(&vec_store, &embedding, Some(&values))?;
+                writes.push(VectorWrite::Store {
+                    id,
+                    offset,
+                    values_offset,
+                    metadata,
+                    appended: true,
+                });
+            }
+            (operation, _) => writes.push(VectorWrite::Delete {
+                id: operation.vector_id().clone(),
+            }),
+        }
+    }
+
+    if branch == "main" {
+        commit_vector_writes(vec_store.clone(), version, &writes, None)
+    } else {
+        commit_branch_writes(vec_store.clone(), branch, version, &writes)
+    }
+}
+
+// Indexes the embeddings of a version that was just made visible, and persists
+// the touched nodes to `{version}.index`. The version is visible before it's
+// indexed, which is safe as searches also scan the embeddings that aren't
+// indexed yet, so its vectors are found either way. Indexing it first would
+// make them reachable through the graph before the version is. On failure the
+// background worker is left to retry, and the error is returned
+fn index_version(
+    vec_store: Arc<VectorStore>,
+    version: u32,
+    upload_process_batch_size: usize,
+) -> Result<(), WaCustomError> {
+    if let Err(e) = index_new_embeddings(&vec_store, version, upload_process_batch_size) {
+        start_indexing(vec_store, upload_process_batch_size);
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Writes the nodes queued by indexing to `{version}.index`
+fn persist_new_nodes(vec_store: Arc<VectorStore>, version: u32) -> Result<(), WaCustomError> {
+    let ver_file = Rc::new(RefCell::new(
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(vec_store.collection_path.join(format!("{}.index", version)))
+            .map_err(|e| {
+                WaCustomError::FsError(format!("Failed to open new version file: {}", e))
+            })?,
+    ));
+    let mut writer = CustomBufferedWriter::new(ver_file.clone())
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    auto_commit_transaction(vec_store, &mut writer)?;
+    writer
+        .flush()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))
+}
+
+// Brings "main" back to how it was in version `target` with a new version that
+// undoes the changes made since, so that the history stays append-only. The
+// vectors of `target` point at their embeddings in `vec_raw.0` again, and the
+// graph is rebuilt from them aside. Nothing changes for readers until the new
+// version is committed, then the rebuilt graph is swapped in
+pub fn rollback_to_version(
+    vec_store: Arc<VectorStore>,
+    target: &VersionSelector,
+    upload_process_batch_size: usize,
+) -> Result<VersionHash, WaCustomError> {
+    let target = resolve_version(vec_store.clone(), "main", target)?;
+    let current_version = vec_store
+        .get_current_version()
+        .ok_or_else(|| WaCustomError::NotFound("current version".to_string()))?;
+    if target == current_version.version {
+        return Err(WaCustomError::InvalidParams);
+    }
+    let version = current_version.version + 1;
+
+    let current_vectors = retrieve_vectors_as_of(vec_store.clone(), current_version.version)?;
+    let target_vectors = retrieve_vectors_as_of(vec_store.clone(), target)?;
+
+    let mut writes = Vec::new();
+    for (key, vector) in &current_vectors {
+        if !target_vectors.contains_key(key) {
+            writes.push(VectorWrite::Delete {
+                id: fetch_embedding_at(vec_store.clone(), vector.offset)?.hash_vec,
+            });
+        }
+    }
+    for (key, vector) in &target_vectors {
+        if current_vectors.get(key).map(|current| current.offset) == Some(vector.offset) {
+            continue;
+        }
+        // the original values are still mapped from the old offset
+        writes.push(VectorWrite::Store {
+            id: fetch_embedding_at(vec_store.clone(), vector.offset)?.hash_vec,
+            offset: vector.offset,
+            values_offset: None,
+            metadata: vector.metadata.clone(),
+            appended: false,
+        });
+    }
+
+    let _index_guard = vec_store.index_lock.lock().unwrap();
+
+    let (new_store, root_path) = new_graph_store(
+        &vec_store,
+        "0.index.rollback",
+        vec_store.max_cache_level,
+        vec_store.hnsw_params,
+    )?;
+    let result = build_index(
+        new_store.clone(),
+        upload_process_batch_size,
+        |vector_id| {
+            Ok(target_vectors
+                .get(&vector_id.to_string())
+                .map(|vector| vector.offset))
+        },
+        |_| true,
+    )
+    .and_then(|built| {
+        persist_new_nodes(new_store.clone(), version)?;
+        // the root chain is the same as the current one, so `0.index` can be
+        // replaced before the version is visible
+        rename(&root_path, vec_store.collection_path.join("0.index"))
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+        Ok(built)
+    });
+    let (count_indexed, next_file_offset) = match result {
+        Ok(built) => built.map_or((0, 0), |(count_indexed, next_file_offset, _)| {
+            (count_indexed, next_file_offset)
+        }),
+        Err(e) => {
+            let _ = remove_file(&root_path);
+            return Err(e);
+        }
+    };
+
+    // everything in `vec_raw.0` is either in the rebuilt graph or stale
+    let (version_hash, _) = commit_vector_writes(
+        vec_store.clone(),
+        version,
+        &writes,
+        Some((count_indexed, next_file_offset)),
+    )?;
+    swap_graph_store(&vec_store, &new_store)?;
+
+    Ok(version_hash)
+}
+
+// Resolves a version of `branch` to its number. The versions of a branch
+// start with those of its parent, up to the one it was created from
+pub fn resolve_version(
+    vec_store: Arc<VectorStore>,
+    branch: &str,
+    selector: &VersionSelector,
+) -> Result<u32, WaCustomError> {
+    let is_selected = |version: &VersionHash| match selector {
+        VersionSelector::Number(number) => version.version == *number,
+        VersionSelector::Hash(hash) => version.hash == *hash,
+    };
+
+    // each branch the versions can be from, with the last version taken from it
+    let mut lineage = Vec::new();
+    {
+        let hasher = vec_store.version_hasher.lock().unwrap();
+        let head = hasher
+            .branch(branch)
+            .map(|branch_info| branch_info.head(branch))
+            .ok_or_else(|| WaCustomError::NotFound(format!("branch {}", branch)))?;
+        if is_selected(&head) {
+            return Ok(head.version);
+        }
+
+        let mut branch = branch.to_string();
+        let mut up_to = head.version;
+        while let Some(branch_info) = hasher.branch(&branch) {
+            let parent_branch = branch_info.parent_branch.clone();
+            let parent_version = branch_info.parent_version;
+            lineage.push((branch, up_to));
+            if parent_branch.is_empty() {
+                break;
+            }
+            branch = parent_branch;
+            up_to = parent_version;
+        }
+    }
+
+    retrieve_versions(vec_store)?
+        .into_iter()
+        .map(|version_info| version_info.version_hash)
+        .find(|version| {
+            is_selected(version)
+                && lineage
+                    .iter()
+                    .any(|(branch, up_to)| &version.branch == branch && version.version <= *up_to)
+        })
+        .map(|version| version.version)
+        .ok_or_else(|| {
+            WaCustomError::NotFound(match selector {
+                VersionSelector::Number(number) => format!("version {}", number),
+                VersionSelector::Hash(hash) => format!("version {}", hash),
+            })
+        })
+}
+
+// A version other than the current one of "main", which can only be read from
+// the history. For another branch, that's any version, its head by default
+fn past_version(
+    vec_store: &VectorStore,
+    branch: &str,
+    version: Option<u32>,
+) -> Result<Option<u32>, WaCustomError> {
+    if branch != "main" {
+        return match version {
+            Some(version) => Ok(Some(version)),
+            None => vec_store
+                .version_hasher
+                .lock()
+                .unwrap()
+                .branch(branch)
+                .map(|branch_info| Some(branch_info.current_version))
+                .ok_or_else(|| WaCustomError::NotFound(format!("branch {}", branch))),
+        };
+    }
+    let current_version = vec_store
+        .get_current_version()
+        .map(|version| version.version);
+    Ok(version.filter(|version| Some(*version) != current_version))
+}
+
+// Whether the vector exists in the head of the transaction's branch, the
+// version the transaction is based on
+fn exists_in_head(
+    vec_store: Arc<VectorStore>,
+    transaction: &VersionHash,
+    vector_id: &VectorId,
+) -> Result<bool, WaCustomError> {
+    if transaction.branch == "main" {
+        return embedding_exists(vec_store, vector_id);
+    }
+    Ok(retrieve_branch_vector_as_of(
+        vec_store,
+        &transaction.branch,
+        vector_id,
+        transaction.version - 1,
+    )?
+    .is_some())
+}
+
+// Versions of every branch, oldest first
+pub fn list_versions(vec_store: Arc<VectorStore>) -> Result<Vec<VersionInfo>, WaCustomError> {
+    let mut versions = retrieve_versions(vec_store)?;
+    versions.sort_by(|a, b| {
+        (a.version_hash.version, &a.version_hash.branch)
+            .cmp(&(b.version_hash.version, &b.version_hash.branch))
+    });
+    Ok(versions)
+}
+
+// Vectors of "main" that were inserted, updated or deleted going from version
+// `from` to version `to`
+pub fn diff_versions(
+    vec_store: Arc<VectorStore>,
+    from: &VersionSelector,
+    to: &VersionSelector,
+) -> Result<VersionDiff, WaCustomError> {
+    let from = resolve_version(vec_store.clone(), "main", from)?;
+    let to = resolve_version(vec_store.clone(), "main", to)?;
+    let changes = retrieve_changes_between(vec_store.clone(), from, to)?;
+
+    // the keys don't tell string ids from int ids, the embeddings do
+    let vector_id = |offset: u32| -> Result<VectorIdValue, WaCustomError> {
+        Ok(VectorIdValue::from(
+            fetch_embedding_at(vec_store.clone(), offset)?.hash_vec,
+        ))
+    };
+
+    let mut diff = VersionDiff {
+        from,
+        to,
+        inserted: Vec::new(),
+        updated: Vec::new(),
+        deleted: Vec::new(),
+    };
+    for (before, after) in changes.values() {
+        match (before, after) {
+            (None, Some(after)) => diff.inserted.push(vector_id(after.offset)?),
+            (Some(before), Some(after)) if before.offset != after.offset => {
+                diff.updated.push(vector_id(after.offset)?)
+            }
+            (Some(before), None) => diff.deleted.push(vector_id(before.offset)?),
+            _ => {}
+        }
+    }
+
+    Ok(diff)
+}
+
+// Creates a branch from a version of `parent_branch`, or from its latest
+// version if none is given
+pub fn create_vector_store_branch(
+    vec_store: Arc<VectorStore>,
+    branch: &str,
+    parent_branch: &str,
+    parent_version: Option<u32>,
+) -> Result<BranchDetails, WaCustomError> {
+    if !is_valid_name(branch) {
+        return Err(WaCustomError::InvalidParams);
+    }
+
+    let head = vec_store
+        .version_hasher
+        .lock()
+        .unwrap()
+        .branch(parent_branch)
+        .map(|branch_info| branch_info.head(parent_branch))
+        .ok_or_else(|| WaCustomError::NotFound(format!("branch {}", parent_branch)))?;
+
+    let parent = match parent_version {
+        None => head,
+        Some(version) if version == head.version => head,
+        Some(version) => retrieve_versions(vec_store.clone())?
+            .into_iter()
+            .map(|version_info| version_info.version_hash)
+            .find(|v| v.branch == parent_branch && v.version == version)
+            .ok_or_else(|| {
+                WaCustomError::NotFound(format!("version {} of branch {}", version, parent_branch))
+            })?,
+    };
+
+    let branch_info = create_branch(vec_store, branch, &parent)?;
+    Ok(branch_details(branch, &branch_info))
+}
+
+pub fn list_vector_store_branches(vec_store: Arc<VectorStore>) -> Vec<BranchDetails> {
+    let hasher = vec_store.version_hasher.lock().unwrap();
+    let mut branches: Vec<BranchDetails> = hasher
+        .branches()
+        .iter()
+        .map(|(name, branch_info)| branch_details(name, branch_info))
+        .collect();
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+    branches
+}
+
+fn branch_details(name: &str, branch_info: &BranchInfo) -> BranchDetails {
+    let parent = (!branch_info.parent_branch.is_empty()).then(|| VersionHash {
+        branch: branch_info.parent_branch.clone(),
+        version: branch_info.parent_version,
+        hash: branch_info.parent_hash.clone(),
+    });
+
+    BranchDetails {
+        name: name.to_string(),
+        head: branch_info.head(name),
+        parent,
+    }
+}
+
+// Returns the stored embedding of a vector, the original values it was
+// upserted with if they're kept, its metadata and its neighbors, `None` if
+// there's no vector with that id. The vector is returned as it is in `branch`,
+// or as it was in `version` of it
+pub async fn fetch_vector(
+    vec_store: Arc<VectorStore>,
+    vector_id: VectorId,
+    branch: &str,
+    version: Option<u32>,
+) -> Result<
+    Option<(
+        VectorEmbedding,
+        Option<Vec<f32>>,
+        Option<Metadata>,
+        Vec<(VectorId, MetricResult)>,
+    )>,
+    WaCustomError,
+> {
+    // the graph only has the neighbors of the latest version of "main"
+    if let Some(version) = past_version(&vec_store, branch, version)? {
+        let Some(vector) =
+            retrieve_branch_vector_as_of(vec_store.clone(), branch, &vector_id, version)?
+        else {
+            return Ok(None);
+        };
+        let embedding = fetch_embedding_at(vec_store.clone(), vector.offset)?;
+        let values = fetch_values_at(vec_store, vector.offset)?;
+        return Ok(Some((embedding, values, vector.metadata, Vec::new())));
+    }
+
+    let Some(offset) = embedding_offset(vec_store.clone(), &vector_id)? else {
+        return Ok(None);
+    };
+    let embedding = fetch_embedding_at(vec_store.clone(), offset)?;
+    let values = fetch_values_at(vec_store.clone(), offset)?;
+    let metadata = retrieve_vector_metadata(vec_store.clone(), &vector_id)?;
+    let neighbors = fetch_vector_neighbors(vec_store, vector_id)
+        .await
+        .into_iter()
+        .flatten()
+        .flat_map(|(_, neighbors)| neighbors)
+        .collect();
+
+    Ok(Some((embedding, values, metadata, neighbors)))
+}
+
 fn calculate_statistics(_: &[i32]) -> Option<Statistics> {
     // Placeholder for calculating statistics
     None
@@ -6,8 +6,26 @@ use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub server: Server,
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
     pub upload_threshold: u32,
-    pub upload_process_batch_size: usize
+    pub upload_process_batch_size: usize,
+    #[serde(default = "default_write_lock_timeout_ms")]
+    pub write_lock_timeout_ms: u64,
+    #[serde(default = "default_transaction_idle_timeout_ms")]
+    pub transaction_idle_timeout_ms: u64,
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from("./data")
+}
+
+fn default_write_lock_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_transaction_idle_timeout_ms() -> u64 {
+    60000
 }
 
 #[derive(Deserialize, Clone)]
@@ -1,5 +1,4 @@
 mod api_service;
-use crate::models::cache_loader::load_cache;
 mod models;
 mod vector_store;
 mod web_server;
@@ -19,6 +18,5 @@ use crate::models::common::*;
 fn main() {
 
     let _ = run_actix_server();
-    load_cache();
     ()
 }
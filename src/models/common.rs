@@ -1,9 +1,9 @@
 use super::dot_product::x86_64::dot_product_u8_avx2;
 use super::lazy_load::LazyItem;
-use super::rpc::VectorIdValue;
+use super::rpc::{VectorIdValue, VersionSelector};
 use super::types::{MergedNode, MetricResult, VectorId};
 use crate::distance::DistanceError;
-use crate::models::rpc::Vector;
+use crate::models::rpc::{Metadata, Vector};
 use crate::models::types::PropState;
 use crate::models::types::VectorQt;
 use crate::quantization::QuantizationError;
@@ -351,6 +351,7 @@ pub enum WaCustomError {
     CalculationError,
     FsError(String),
     DeserializationError(String),
+    NotFound(String),
 }
 
 impl fmt::Display for WaCustomError {
@@ -372,6 +373,7 @@ impl fmt::Display for WaCustomError {
             WaCustomError::CalculationError => write!(f, "Calculation error"),
             WaCustomError::FsError(err) => write!(f, "FS error: {}", err),
             WaCustomError::DeserializationError(err) => write!(f, "Deserialization error: {}", err),
+            WaCustomError::NotFound(what) => write!(f, "Not found: {}", what),
         }
     }
 }
@@ -458,18 +460,26 @@ pub fn convert_option_vec(
     })
 }
 
-// Function to convert Vec<Vector> to Vec<(VectorIdValue, Vec<f32>)>
-pub fn convert_vectors(vectors: Vec<Vector>) -> Vec<(VectorIdValue, Vec<f32>)> {
+// Function to convert Vec<Vector> to Vec<(VectorIdValue, Vec<f32>, Option<Metadata>)>
+pub fn convert_vectors(vectors: Vec<Vector>) -> Vec<(VectorIdValue, Vec<f32>, Option<Metadata>)> {
     vectors
         .into_iter()
-        .map(|vector| (vector.id.clone(), vector.values))
+        .map(|vector| (vector.id.clone(), vector.values, vector.metadata))
         .collect()
 }
 
+// Ranks the candidates found across all the levels and returns the `k`
+// closest distinct vectors
 pub fn remove_duplicates_and_filter(
     input: Option<Vec<(LazyItem<MergedNode>, MetricResult)>>,
+    k: usize,
 ) -> Option<Vec<(VectorId, MetricResult)>> {
-    input.map(|vec| {
+    input.map(|mut vec| {
+        vec.sort_by(|a, b| {
+            b.1.get_similarity()
+                .partial_cmp(&a.1.get_similarity())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
         let mut seen = HashSet::new();
         vec.into_iter()
             .filter_map(|(lazy_item, similarity)| {
@@ -498,6 +508,7 @@ pub fn remove_duplicates_and_filter(
                     None // data is None
                 }
             })
+            .take(k)
             .collect()
     })
 }
@@ -543,6 +554,16 @@ impl From<VectorIdValue> for VectorId {
     }
 }
 
+// Versions in request paths are given either by number or by hash
+impl From<String> for VersionSelector {
+    fn from(version: String) -> Self {
+        match version.parse() {
+            Ok(number) => VersionSelector::Number(number),
+            Err(_) => VersionSelector::Hash(version),
+        }
+    }
+}
+
 pub fn cat_maybes<T>(iter: impl Iterator<Item = Option<T>>) -> Vec<T> {
     iter.flat_map(|maybe| maybe).collect()
 }
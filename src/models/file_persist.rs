@@ -109,6 +109,24 @@ pub fn load_vector_id_lsmdb(_level: HNSWLevel, _vector_id: VectorId) -> LazyItem
 pub fn load_neighbor_persist_ref(_level: HNSWLevel, _node_file_ref: u32) -> Option<MergedNode> {
     None
 }
+pub fn read_prop_from_file(
+    (offset, bytes_to_read): (FileOffset, BytesToRead),
+    mut file: &File,
+) -> Result<NodeProp, WaCustomError> {
+    let mut prop_bytes = vec![0; bytes_to_read.0 as usize];
+
+    file.seek(SeekFrom::Start(offset.0 as u64))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    file.read_exact(&mut prop_bytes)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let mut prop: NodeProp = serde_cbor::from_slice(&prop_bytes)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    prop.location = Some((offset, bytes_to_read));
+
+    Ok(prop)
+}
+
 pub fn write_prop_to_file(prop: &NodeProp, mut file: &File) -> (FileOffset, BytesToRead) {
     let mut prop_bytes = Vec::new();
     //let result = encode(&prop);
@@ -0,0 +1,163 @@
+use super::payload_index::Bitmap;
+use super::rpc::{ComparisonOperator, Filter, LogicalOperator, Metadata, MetadataColumnValue};
+use std::cmp::Ordering;
+
+// A search filter along with the candidate ids the collection's payload
+// indexes narrowed it down to, if any
+pub struct SearchFilter<'a> {
+    pub filter: &'a Filter,
+    pub candidates: Option<Bitmap>,
+}
+
+impl Filter {
+    // Evaluates the filter against the metadata of a vector. A comparison on a
+    // column the vector doesn't have never matches (including `$ne` and `$nin`)
+    pub fn matches(&self, metadata: Option<&Metadata>) -> bool {
+        match self {
+            Filter::Comparison { column } => column.iter().all(|(name, op)| {
+                metadata
+                    .and_then(|metadata| metadata.get(name))
+                    .map_or(false, |value| op.matches(value))
+            }),
+            Filter::Logical(LogicalOperator::And(filters)) => {
+                filters.iter().all(|filter| filter.matches(metadata))
+            }
+            Filter::Logical(LogicalOperator::Or(filters)) => {
+                filters.iter().any(|filter| filter.matches(metadata))
+            }
+        }
+    }
+}
+
+impl ComparisonOperator {
+    pub fn matches(&self, value: &MetadataColumnValue) -> bool {
+        match self {
+            ComparisonOperator::Eq(x) => value.compare(x) == Some(Ordering::Equal),
+            ComparisonOperator::Ne(x) => value.compare(x) != Some(Ordering::Equal),
+            ComparisonOperator::Gt(x) => value.compare(x) == Some(Ordering::Greater),
+            ComparisonOperator::Gte(x) => {
+                matches!(value.compare(x), Some(Ordering::Greater | Ordering::Equal))
+            }
+            ComparisonOperator::Lt(x) => value.compare(x) == Some(Ordering::Less),
+            ComparisonOperator::Lte(x) => {
+                matches!(value.compare(x), Some(Ordering::Less | Ordering::Equal))
+            }
+            ComparisonOperator::In(xs) => {
+                xs.iter().any(|x| value.compare(x) == Some(Ordering::Equal))
+            }
+            ComparisonOperator::Nin(xs) => {
+                xs.iter().all(|x| value.compare(x) != Some(Ordering::Equal))
+            }
+        }
+    }
+}
+
+impl MetadataColumnValue {
+    // Strings only compare with strings, ints and floats compare numerically
+    // with each other. Values of incompatible types are not ordered
+    pub fn compare(&self, other: &MetadataColumnValue) -> Option<Ordering> {
+        match (self, other) {
+            (Self::StringValue(a), Self::StringValue(b)) => Some(a.cmp(b)),
+            (Self::IntValue(a), Self::IntValue(b)) => Some(a.cmp(b)),
+            (Self::IntValue(a), Self::FloatValue(b)) => (*a as f64).partial_cmp(b),
+            (Self::FloatValue(a), Self::IntValue(b)) => a.partial_cmp(&(*b as f64)),
+            (Self::FloatValue(a), Self::FloatValue(b)) => a.partial_cmp(b),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn metadata() -> Metadata {
+        serde_json::from_value(json!({
+            "title": "Dune",
+            "year": 1965,
+            "rating": 4.5,
+        }))
+        .unwrap()
+    }
+
+    fn filter(value: serde_json::Value) -> Filter {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_comparison_operators() {
+        let metadata = metadata();
+
+        let cases = [
+            (json!({"title": {"$eq": "Dune"}}), true),
+            (json!({"title": {"$eq": "Emma"}}), false),
+            (json!({"title": {"$ne": "Emma"}}), true),
+            (json!({"year": {"$gt": 1965}}), false),
+            (json!({"year": {"$gte": 1965}}), true),
+            (json!({"year": {"$lt": 1965.5}}), true),
+            (json!({"rating": {"$lte": 4}}), false),
+            (json!({"rating": {"$eq": 4.5}}), true),
+            (json!({"title": {"$in": ["Emma", "Dune"]}}), true),
+            (json!({"title": {"$nin": ["Emma", "Dune"]}}), false),
+            (json!({"year": {"$eq": "1965"}}), false),
+        ];
+
+        for (value, expected) in cases {
+            let matches = filter(value.clone()).matches(Some(&metadata));
+            assert_eq!(matches, expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_missing_column() {
+        let metadata = metadata();
+
+        assert!(!filter(json!({"author": {"$ne": "Herbert"}})).matches(Some(&metadata)));
+        assert!(!filter(json!({"author": {"$nin": ["Herbert"]}})).matches(Some(&metadata)));
+        assert!(!filter(json!({"title": {"$eq": "Dune"}})).matches(None));
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        let metadata = metadata();
+
+        let and = filter(json!({"$and": [
+            {"title": {"$eq": "Dune"}},
+            {"year": {"$lt": 1970}},
+        ]}));
+        assert!(and.matches(Some(&metadata)));
+
+        let and = filter(json!({"$and": [
+            {"title": {"$eq": "Dune"}},
+            {"year": {"$gt": 1970}},
+        ]}));
+        assert!(!and.matches(Some(&metadata)));
+
+        let or = filter(json!({"$or": [
+            {"title": {"$eq": "Emma"}},
+            {"$and": [{"year": {"$gte": 1965}}, {"rating": {"$gt": 4}}]},
+        ]}));
+        assert!(or.matches(Some(&metadata)));
+
+        let or = filter(json!({"$or": [
+            {"title": {"$eq": "Emma"}},
+            {"rating": {"$lt": 4}},
+        ]}));
+        assert!(!or.matches(Some(&metadata)));
+    }
+
+    #[test]
+    fn test_multiple_columns_must_all_match() {
+        let metadata = metadata();
+
+        assert!(
+            filter(json!({"title": {"$eq": "Dune"}, "year": {"$eq": 1965}}))
+                .matches(Some(&metadata))
+        );
+        assert!(
+            !filter(json!({"title": {"$eq": "Dune"}, "year": {"$eq": 1966}}))
+                .matches(Some(&metadata))
+        );
+    }
+}
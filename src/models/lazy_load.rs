@@ -385,6 +385,16 @@ where
         vec.into_iter()
     }
 
+    // Keeps only the items `f` returns true for
+    pub fn retain(&self, f: impl Fn(&EagerLazyItem<T, E>) -> bool) {
+        let mut arc = self.items.clone();
+
+        arc.transactional_update(|set| {
+            IdentitySet::from_iter(set.iter().filter(|item| f(item)).cloned())
+        })
+        .unwrap();
+    }
+
     pub fn is_empty(&self) -> bool {
         let mut arc = self.items.clone();
         arc.get().is_empty()
@@ -1,40 +1,215 @@
 use crate::models::common::*;
+use crate::models::rpc::Metadata;
 use crate::models::types::*;
 use crate::models::versioning::*;
-use lmdb::{Transaction, WriteFlags};
+use lmdb::{Cursor, Database, Environment, RoCursor, RwTransaction, Transaction, WriteFlags};
+use std::array::TryFromSliceError;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+// Generates the hash of the next version of `branch` and moves the branch head
+// to it, making it the collection's current version if the branch is "main".
+// The version is persisted along with it
 pub fn store_current_version(
     vec_store: Arc<VectorStore>,
     branch: String,
     version: u32,
+    operations: OperationCounts,
 ) -> Result<VersionHash, WaCustomError> {
-    let mut hasher = VersionHasher::new();
-    // Generate hashes for main branch
-    let hash = hasher.generate_hash(&branch, version, None, None);
     let env = vec_store.lmdb.env.clone();
-    let db = vec_store.lmdb.metadata_db.clone();
-
     let mut txn = env
         .begin_rw_txn()
         .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
+    let mut hasher = vec_store.version_hasher.lock().unwrap();
+    // the in-memory heads only move once the version is persisted
+    let (hash, next_hasher) = put_current_version(
+        &mut txn,
+        &vec_store.lmdb,
+        &hasher,
+        &branch,
+        version,
+        operations,
+    )?;
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    *hasher = next_hasher;
+
+    Ok(hash)
+}
+
+// Same as `store_current_version`, as part of a larger LMDB transaction. The
+// hasher with the moved branch head is returned, to replace `hasher` once the
+// transaction is committed. The heads of the branches other than "main" are
+// only kept in the branches db
+pub fn put_current_version(
+    txn: &mut RwTransaction,
+    lmdb: &MetaDb,
+    hasher: &VersionHasher,
+    branch: &str,
+    version: u32,
+    operations: OperationCounts,
+) -> Result<(VersionHash, VersionHasher), WaCustomError> {
+    let mut next_hasher = hasher.clone();
+    let hash = next_hasher.generate_hash(branch, version, None, None);
+    let version_info = new_version_info(hasher, &hash, operations);
+
     let serialized = rkyv::to_bytes::<_, 256>(&hash)
         .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
 
+    if branch == "main" {
+        txn.put(
+            *lmdb.metadata_db.as_ref(),
+            &"current_version".to_string(),
+            &serialized,
+            WriteFlags::empty(),
+        )
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    }
+
+    put_branch(txn, lmdb, branch, next_hasher.branch(branch).unwrap())?;
+    put_version(txn, lmdb, &version_info)?;
+
+    Ok((hash, next_hasher))
+}
+
+// Creates a branch starting at `parent`, fails with `InvalidParams` if a
+// branch with that name already exists
+pub fn create_branch(
+    vec_store: Arc<VectorStore>,
+    branch: &str,
+    parent: &VersionHash,
+) -> Result<BranchInfo, WaCustomError> {
+    let mut hasher = vec_store.version_hasher.lock().unwrap();
+    let mut next_hasher = hasher.clone();
+    let branch_info = next_hasher
+        .create_branch(branch, parent)
+        .ok_or(WaCustomError::InvalidParams)?
+        .clone();
+    let env = vec_store.lmdb.env.clone();
+
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    put_branch(&mut txn, &vec_store.lmdb, branch, &branch_info)?;
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    *hasher = next_hasher;
+
+    Ok(branch_info)
+}
+
+fn put_branch(
+    txn: &mut RwTransaction,
+    lmdb: &MetaDb,
+    branch: &str,
+    branch_info: &BranchInfo,
+) -> Result<(), WaCustomError> {
+    let serialized = serde_cbor::to_vec(branch_info)
+        .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
+
     txn.put(
-        *db.as_ref(),
-        &"current_version".to_string(),
+        *lmdb.branches_db.as_ref(),
+        &branch,
         &serialized,
         WriteFlags::empty(),
     )
-    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))
+}
 
-    txn.commit().map_err(|e| {
-        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
-    })?;
+// Describes `hash`, generated by a hasher whose heads were `hasher`
+fn new_version_info(
+    hasher: &VersionHasher,
+    hash: &VersionHash,
+    operations: OperationCounts,
+) -> VersionInfo {
+    let parent = hasher
+        .branch(&hash.branch)
+        .map(|branch_info| branch_info.current_hash.clone())
+        .filter(|parent| !parent.is_empty());
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
 
-    Ok(hash)
+    VersionInfo {
+        version_hash: hash.clone(),
+        parent,
+        timestamp,
+        operations,
+    }
+}
+
+fn put_version(
+    txn: &mut RwTransaction,
+    lmdb: &MetaDb,
+    version_info: &VersionInfo,
+) -> Result<(), WaCustomError> {
+    let serialized = serde_cbor::to_vec(version_info)
+        .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
+
+    txn.put(
+        *lmdb.versions_db.as_ref(),
+        &version_info.version_hash.hash,
+        &serialized,
+        WriteFlags::empty(),
+    )
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))
+}
+
+pub fn retrieve_branches(
+    vec_store: Arc<VectorStore>,
+) -> Result<HashMap<String, BranchInfo>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let db = vec_store.lmdb.branches_db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut cursor = txn
+        .open_ro_cursor(*db.as_ref())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let mut branches = HashMap::new();
+    for (key, value) in cursor.iter() {
+        let branch = String::from_utf8(key.to_vec())
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        let branch_info = serde_cbor::from_slice(value).map_err(|e| {
+            WaCustomError::DeserializationError(format!("Failed to deserialize BranchInfo: {}", e))
+        })?;
+        branches.insert(branch, branch_info);
+    }
+
+    Ok(branches)
+}
+
+pub fn retrieve_versions(vec_store: Arc<VectorStore>) -> Result<Vec<VersionInfo>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let db = vec_store.lmdb.versions_db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut cursor = txn
+        .open_ro_cursor(*db.as_ref())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let mut versions = Vec::new();
+    for (_, value) in cursor.iter() {
+        let version_info = serde_cbor::from_slice(value).map_err(|e| {
+            WaCustomError::DeserializationError(format!("Failed to deserialize VersionInfo: {}", e))
+        })?;
+        versions.push(version_info);
+    }
+
+    Ok(versions)
 }
 
 pub fn retrieve_current_version(vec_store: Arc<VectorStore>) -> Result<VersionHash, WaCustomError> {
@@ -53,9 +228,550 @@ pub fn retrieve_current_version(vec_store: Arc<VectorStore>) -> Result<VersionHa
             _ => WaCustomError::DatabaseError(e.to_string()),
         })?;
 
-    let version_hash = unsafe { rkyv::from_bytes_unchecked(serialized_hash) }.map_err(|e| {
+    // LMDB doesn't align the values it returns the way rkyv requires
+    let mut aligned = rkyv::AlignedVec::new();
+    aligned.extend_from_slice(serialized_hash);
+
+    let version_hash = unsafe { rkyv::from_bytes_unchecked(&aligned) }.map_err(|e| {
         WaCustomError::SerializationError(format!("Failed to deserialize VersionHash: {}", e))
     })?;
 
     Ok(version_hash)
 }
+
+pub fn store_collection_config(
+    env: Arc<Environment>,
+    db: Arc<Database>,
+    config: &CollectionConfig,
+) -> Result<(), WaCustomError> {
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let serialized = serde_cbor::to_vec(config)
+        .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
+
+    txn.put(*db.as_ref(), &config.name, &serialized, WriteFlags::empty())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(())
+}
+
+pub fn retrieve_collection_configs(
+    env: Arc<Environment>,
+    db: Arc<Database>,
+) -> Result<Vec<CollectionConfig>, WaCustomError> {
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut cursor = txn
+        .open_ro_cursor(*db.as_ref())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let mut configs = Vec::new();
+    for (_, value) in cursor.iter() {
+        let config = serde_cbor::from_slice(value).map_err(|e| {
+            WaCustomError::DeserializationError(format!(
+                "Failed to deserialize CollectionConfig: {}",
+                e
+            ))
+        })?;
+        configs.push(config);
+    }
+
+    Ok(configs)
+}
+
+pub fn delete_collection_config(
+    env: Arc<Environment>,
+    db: Arc<Database>,
+    name: &str,
+) -> Result<(), WaCustomError> {
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    match txn.del(*db.as_ref(), &name, None) {
+        Ok(()) | Err(lmdb::Error::NotFound) => {}
+        Err(e) => {
+            return Err(WaCustomError::DatabaseError(format!(
+                "Failed to delete data: {}",
+                e
+            )))
+        }
+    }
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(())
+}
+
+// Reads one of the `u32` counters (`count_indexed`, `count_unindexed`, ...)
+// kept in the metadata db, a missing counter is reported as 0
+pub fn retrieve_counter(vec_store: Arc<VectorStore>, key: &str) -> Result<u32, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let db = vec_store.lmdb.metadata_db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let count = match txn.get(*db.as_ref(), &key) {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => 0,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    Ok(count)
+}
+
+pub fn retrieve_vector_metadata(
+    vec_store: Arc<VectorStore>,
+    vector_id: &VectorId,
+) -> Result<Option<Metadata>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let db = vec_store.lmdb.payloads_db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let serialized = match txn.get(*db.as_ref(), &vector_id.to_string()) {
+        Ok(bytes) => bytes,
+        Err(lmdb::Error::NotFound) => return Ok(None),
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    let metadata = serde_cbor::from_slice(serialized).map_err(|e| {
+        WaCustomError::DeserializationError(format!("Failed to deserialize metadata: {}", e))
+    })?;
+
+    Ok(Some(metadata))
+}
+
+// Returns the metadata of every vector in the collection, along with the key
+// it's stored under
+pub fn retrieve_all_vector_metadata(
+    vec_store: Arc<VectorStore>,
+) -> Result<Vec<(String, Metadata)>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let db = vec_store.lmdb.payloads_db.clone();
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut cursor = txn
+        .open_ro_cursor(*db.as_ref())
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let mut entries = Vec::new();
+    for (key, value) in cursor.iter() {
+        let key = String::from_utf8(key.to_vec())
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        let metadata = serde_cbor::from_slice(value).map_err(|e| {
+            WaCustomError::DeserializationError(format!("Failed to deserialize metadata: {}", e))
+        })?;
+        entries.push((key, metadata));
+    }
+
+    Ok(entries)
+}
+
+// The version is zero-padded so that the history db iterates in version order
+fn history_key(version: u32, vector_id: &str) -> String {
+    format!("{:010}:{}", version, vector_id)
+}
+
+// Same as `history_key` the other way around, so that the changes made to a
+// vector are next to each other in version order
+fn vector_history_key(vector_id: &str, version: u32) -> String {
+    format!("{}:{:010}", vector_id, version)
+}
+
+// Same as `history_key` for a branch other than "main", whose changes are all
+// next to each other in version order
+fn branch_history_key(branch: &str, version: u32, vector_id: &str) -> String {
+    format!("{}:{:010}:{}", branch, version, vector_id)
+}
+
+// Records a change made to a vector in `version`, replacing any change to the
+// same vector recorded earlier in that version
+pub fn put_vector_change(
+    txn: &mut RwTransaction,
+    lmdb: &MetaDb,
+    version: u32,
+    vector_id: &VectorId,
+    change: &VectorChange,
+) -> Result<(), WaCustomError> {
+    let serialized = serde_cbor::to_vec(change)
+        .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
+
+    let key = vector_id.to_string();
+    txn.put(
+        *lmdb.history_db.as_ref(),
+        &history_key(version, &key),
+        &serialized,
+        WriteFlags::empty(),
+    )
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+
+    txn.put(
+        *lmdb.vector_history_db.as_ref(),
+        &vector_history_key(&key, version),
+        &serialized,
+        WriteFlags::empty(),
+    )
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))
+}
+
+// Same as `put_vector_change`, for a change made on a branch other than "main"
+pub fn put_branch_vector_change(
+    txn: &mut RwTransaction,
+    lmdb: &MetaDb,
+    branch: &str,
+    version: u32,
+    vector_id: &VectorId,
+    change: &VectorChange,
+) -> Result<(), WaCustomError> {
+    let serialized = serde_cbor::to_vec(change)
+        .map_err(|e| WaCustomError::SerializationError(format!("Failed to serialize: {}", e)))?;
+
+    txn.put(
+        *lmdb.branch_history_db.as_ref(),
+        &branch_history_key(branch, version, &vector_id.to_string()),
+        &serialized,
+        WriteFlags::empty(),
+    )
+    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))
+}
+
+// The vectors that existed in `version`, keyed like the embeddings db. Only
+// the vectors changed since are looked up in the history, the others are
+// read from the current state
+pub fn retrieve_vectors_as_of(
+    vec_store: Arc<VectorStore>,
+    version: u32,
+) -> Result<HashMap<String, VectorSnapshot>, WaCustomError> {
+    let lmdb = &vec_store.lmdb;
+    let txn = lmdb
+        .env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut vectors = HashMap::new();
+    {
+        let mut cursor = txn
+            .open_ro_cursor(*lmdb.embeddings_db)
+            .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+        for (key, value) in cursor.iter() {
+            let bytes = value.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            let metadata = match txn.get(*lmdb.payloads_db, &key) {
+                Ok(bytes) => Some(serde_cbor::from_slice(bytes).map_err(|e| {
+                    WaCustomError::DeserializationError(format!(
+                        "Failed to deserialize metadata: {}",
+                        e
+                    ))
+                })?),
+                Err(lmdb::Error::NotFound) => None,
+                Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+            };
+            let key = std::str::from_utf8(key)
+                .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+            vectors.insert(
+                key.to_string(),
+                VectorSnapshot {
+                    offset: u32::from_le_bytes(bytes),
+                    metadata,
+                },
+            );
+        }
+    }
+
+    for key in changed_vectors(&txn, lmdb, version, None)? {
+        match vector_as_of(&txn, lmdb, &key, version)? {
+            Some(vector) => vectors.insert(key, vector),
+            None => vectors.remove(&key),
+        };
+    }
+
+    Ok(vectors)
+}
+
+// A vector as it was in `version`, `None` if it didn't exist then
+pub fn retrieve_vector_as_of(
+    vec_store: Arc<VectorStore>,
+    vector_id: &VectorId,
+    version: u32,
+) -> Result<Option<VectorSnapshot>, WaCustomError> {
+    let lmdb = &vec_store.lmdb;
+    let txn = lmdb
+        .env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    vector_as_of(&txn, lmdb, &vector_id.to_string(), version)
+}
+
+// Same as `retrieve_vectors_as_of` for any branch. A branch has the vectors of
+// the version it was created from, with the changes made on the branch since
+pub fn retrieve_branch_vectors_as_of(
+    vec_store: Arc<VectorStore>,
+    branch: &str,
+    version: u32,
+) -> Result<HashMap<String, VectorSnapshot>, WaCustomError> {
+    let Some((parent_branch, parent_version)) = branch_parent(&vec_store, branch)? else {
+        return retrieve_vectors_as_of(vec_store, version);
+    };
+    if version <= parent_version {
+        return retrieve_branch_vectors_as_of(vec_store, &parent_branch, version);
+    }
+
+    let mut vectors =
+        retrieve_branch_vectors_as_of(vec_store.clone(), &parent_branch, parent_version)?;
+    for (key, change) in branch_changes(&vec_store.lmdb, branch, version)? {
+        match apply_change(vectors.remove(&key), change) {
+            Some(vector) => vectors.insert(key, vector),
+            None => None,
+        };
+    }
+
+    Ok(vectors)
+}
+
+// Same as `retrieve_vector_as_of` for any branch
+pub fn retrieve_branch_vector_as_of(
+    vec_store: Arc<VectorStore>,
+    branch: &str,
+    vector_id: &VectorId,
+    version: u32,
+) -> Result<Option<VectorSnapshot>, WaCustomError> {
+    let Some((parent_branch, parent_version)) = branch_parent(&vec_store, branch)? else {
+        return retrieve_vector_as_of(vec_store, vector_id, version);
+    };
+    if version <= parent_version {
+        return retrieve_branch_vector_as_of(vec_store, &parent_branch, vector_id, version);
+    }
+
+    let key = vector_id.to_string();
+    let mut vector =
+        retrieve_branch_vector_as_of(vec_store.clone(), &parent_branch, vector_id, parent_version)?;
+    for (changed, change) in branch_changes(&vec_store.lmdb, branch, version)? {
+        if changed == key {
+            vector = apply_change(vector, change);
+        }
+    }
+
+    Ok(vector)
+}
+
+// The branch and version `branch` was created from, `None` for "main"
+fn branch_parent(
+    vec_store: &VectorStore,
+    branch: &str,
+) -> Result<Option<(String, u32)>, WaCustomError> {
+    if branch == "main" {
+        return Ok(None);
+    }
+    let hasher = vec_store.version_hasher.lock().unwrap();
+    let branch_info = hasher
+        .branch(branch)
+        .ok_or_else(|| WaCustomError::NotFound(format!("branch {}", branch)))?;
+    Ok(Some((
+        branch_info.parent_branch.clone(),
+        branch_info.parent_version,
+    )))
+}
+
+// The changes made on a branch other than "main" up to and including
+// `version`, in the order they were made, with the keys of their vectors
+fn branch_changes(
+    lmdb: &MetaDb,
+    branch: &str,
+    version: u32,
+) -> Result<Vec<(String, VectorChange)>, WaCustomError> {
+    let txn = lmdb
+        .env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let mut cursor = txn
+        .open_ro_cursor(*lmdb.branch_history_db)
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let prefix = format!("{}:", branch);
+    let mut changes = Vec::new();
+    for (key, value) in iter_from(&mut cursor, &prefix)? {
+        let key = std::str::from_utf8(key)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        // branch names can't have a `:`, so the keys of other branches don't
+        // start with the prefix
+        let Some(rest) = key.strip_prefix(&prefix) else {
+            break;
+        };
+        let (change_version, vector_id) = rest.split_once(':').ok_or_else(|| {
+            WaCustomError::DeserializationError(format!("Invalid history key: {}", key))
+        })?;
+        let change_version: u32 = change_version
+            .parse()
+            .map_err(|e| WaCustomError::DeserializationError(format!("{}", e)))?;
+        if change_version > version {
+            break;
+        }
+
+        let change: VectorChange = serde_cbor::from_slice(value).map_err(|e| {
+            WaCustomError::DeserializationError(format!(
+                "Failed to deserialize VectorChange: {}",
+                e
+            ))
+        })?;
+        changes.push((vector_id.to_string(), change));
+    }
+
+    Ok(changes)
+}
+
+// The vectors changed between two versions, with how they were in each, keyed
+// like the embeddings db. Only the changes recorded in between are read
+pub fn retrieve_changes_between(
+    vec_store: Arc<VectorStore>,
+    from: u32,
+    to: u32,
+) -> Result<HashMap<String, VectorVersions>, WaCustomError> {
+    let lmdb = &vec_store.lmdb;
+    let txn = lmdb
+        .env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut changes = HashMap::new();
+    for key in changed_vectors(&txn, lmdb, from.min(to), Some(from.max(to)))? {
+        let before = vector_as_of(&txn, lmdb, &key, from)?;
+        let after = vector_as_of(&txn, lmdb, &key, to)?;
+        changes.insert(key, (before, after));
+    }
+
+    Ok(changes)
+}
+
+// `MDB_SET_RANGE` from lmdb.h, which the lmdb crate doesn't re-export
+const MDB_SET_RANGE: u32 = 17;
+
+// Iterates from the first key at or after `key`. Unlike `Cursor::iter_from`,
+// which panics if there's no such key, the iterator is then empty
+fn iter_from<'txn>(
+    cursor: &mut RoCursor<'txn>,
+    key: &str,
+) -> Result<impl Iterator<Item = (&'txn [u8], &'txn [u8])>, WaCustomError> {
+    let first = match cursor.get(Some(key.as_bytes()), None, MDB_SET_RANGE) {
+        // the key is always returned for MDB_SET_RANGE
+        Ok((key, value)) => key.map(|key| (key, value)),
+        Err(lmdb::Error::NotFound) => None,
+        Err(e) => return Err(WaCustomError::DatabaseError(e.to_string())),
+    };
+    let rest = first.is_some().then(|| cursor.iter());
+
+    Ok(first.into_iter().chain(rest.into_iter().flatten()))
+}
+
+// Keys of the vectors changed after version `after`, up to and including
+// version `up_to` if it's given
+fn changed_vectors(
+    txn: &impl Transaction,
+    lmdb: &MetaDb,
+    after: u32,
+    up_to: Option<u32>,
+) -> Result<HashSet<String>, WaCustomError> {
+    let mut keys = HashSet::new();
+    let Some(start) = after.checked_add(1) else {
+        return Ok(keys);
+    };
+
+    let mut cursor = txn
+        .open_ro_cursor(*lmdb.history_db)
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+    for (key, _) in iter_from(&mut cursor, &history_key(start, ""))? {
+        let key = std::str::from_utf8(key)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        let (change_version, vector_id) = key.split_once(':').ok_or_else(|| {
+            WaCustomError::DeserializationError(format!("Invalid history key: {}", key))
+        })?;
+        let change_version: u32 = change_version
+            .parse()
+            .map_err(|e| WaCustomError::DeserializationError(format!("{}", e)))?;
+        if up_to.is_some_and(|up_to| change_version > up_to) {
+            break;
+        }
+        keys.insert(vector_id.to_string());
+    }
+
+    Ok(keys)
+}
+
+// Replays the changes recorded for one vector up to and including `version`
+fn vector_as_of(
+    txn: &impl Transaction,
+    lmdb: &MetaDb,
+    key: &str,
+    version: u32,
+) -> Result<Option<VectorSnapshot>, WaCustomError> {
+    let prefix = format!("{}:", key);
+    let mut cursor = txn
+        .open_ro_cursor(*lmdb.vector_history_db)
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to open cursor: {}", e)))?;
+
+    let mut vector: Option<VectorSnapshot> = None;
+    for (history_key, value) in iter_from(&mut cursor, &prefix)? {
+        let history_key = std::str::from_utf8(history_key)
+            .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+        // the version is the last 10 digits, which tells this vector's keys
+        // from those of ids that only start like it
+        let Some(change_version) = history_key
+            .strip_prefix(&prefix)
+            .filter(|version| version.len() == 10)
+        else {
+            if history_key.starts_with(&prefix) {
+                continue;
+            }
+            break;
+        };
+        let change_version: u32 = change_version
+            .parse()
+            .map_err(|e| WaCustomError::DeserializationError(format!("{}", e)))?;
+        if change_version > version {
+            break;
+        }
+
+        let change: VectorChange = serde_cbor::from_slice(value).map_err(|e| {
+            WaCustomError::DeserializationError(format!(
+                "Failed to deserialize VectorChange: {}",
+                e
+            ))
+        })?;
+        vector = apply_change(vector, change);
+    }
+
+    Ok(vector)
+}
+
+// The vector as it is after the change, `vector` being how it was before
+fn apply_change(vector: Option<VectorSnapshot>, change: VectorChange) -> Option<VectorSnapshot> {
+    let offset = change.offset?;
+    let metadata = match change.metadata {
+        // recorded when the metadata was removed
+        Some(metadata) if metadata.is_empty() => None,
+        Some(metadata) => Some(metadata),
+        None => vector.and_then(|snapshot| snapshot.metadata),
+    };
+    Some(VectorSnapshot { offset, metadata })
+}
@@ -5,15 +5,18 @@ pub mod dot_product;
 pub mod dry_run_writer;
 pub mod encoding_format;
 pub mod file_persist;
+pub mod filter;
 pub mod identity_collections;
 pub mod lazy_load;
 pub mod lookup_table;
 pub mod meta_persist;
+pub mod payload_index;
 pub mod rpc;
 pub mod serializer;
 pub mod types;
 pub mod user;
 pub mod versioning;
+pub mod wal;
 
 #[cfg(test)]
 mod custom_buffered_writer_tests;
@@ -0,0 +1,542 @@
+use super::rpc::{ComparisonOperator, Filter, LogicalOperator, Metadata, MetadataColumnValue};
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadIndexKind {
+    // exact lookups on string and int values
+    Hash,
+    // range lookups on float and int values
+    Ordered,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PayloadIndexConfig {
+    pub column: String,
+    pub kind: PayloadIndexKind,
+}
+
+// Set of internal ids
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: u32) {
+        let (word, bit) = (id as usize / 64, id % 64);
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1u64 << bit;
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        let (word, bit) = (id as usize / 64, id % 64);
+        if let Some(word) = self.words.get_mut(word) {
+            *word &= !(1u64 << bit);
+        }
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        let (word, bit) = (id as usize / 64, id % 64);
+        self.words
+            .get(word)
+            .map_or(false, |word| word & (1u64 << bit) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    pub fn union_with(&mut self, other: &Bitmap) {
+        if other.words.len() > self.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    pub fn intersect_with(&mut self, other: &Bitmap) {
+        self.words.truncate(other.words.len());
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    pub fn difference_with(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, word)| {
+            (0..64)
+                .filter(move |bit| word & (1u64 << bit) != 0)
+                .map(move |bit| (i * 64 + bit) as u32)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum HashKey {
+    Str(String),
+    Int(i32),
+}
+
+// `f64` with a total order, so it can be used as a `BTreeMap` key
+#[derive(Debug, Clone, Copy)]
+struct OrderedKey(f64);
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+fn numeric_value(value: &MetadataColumnValue) -> Option<f64> {
+    match value {
+        MetadataColumnValue::IntValue(i) => Some(*i as f64),
+        MetadataColumnValue::FloatValue(f) => Some(*f),
+        MetadataColumnValue::StringValue(_) => None,
+    }
+}
+
+#[derive(Debug)]
+enum PayloadIndex {
+    Hash {
+        values: HashMap<HashKey, Bitmap>,
+        // set once a float value has been seen in the column, numeric lookups
+        // can't be answered by the index after that
+        has_floats: bool,
+    },
+    // string values are left out of the ordered index
+    Ordered {
+        values: BTreeMap<OrderedKey, Bitmap>,
+    },
+}
+
+impl PayloadIndex {
+    fn new(kind: PayloadIndexKind) -> Self {
+        match kind {
+            PayloadIndexKind::Hash => Self::Hash {
+                values: HashMap::new(),
+                has_floats: false,
+            },
+            PayloadIndexKind::Ordered => Self::Ordered {
+                values: BTreeMap::new(),
+            },
+        }
+    }
+
+    fn insert(&mut self, id: u32, value: &MetadataColumnValue) {
+        match self {
+            Self::Hash { values, has_floats } => {
+                let key = match value {
+                    MetadataColumnValue::StringValue(s) => HashKey::Str(s.clone()),
+                    MetadataColumnValue::IntValue(i) => HashKey::Int(*i),
+                    MetadataColumnValue::FloatValue(_) => {
+                        *has_floats = true;
+                        return;
+                    }
+                };
+                values.entry(key).or_default().insert(id);
+            }
+            Self::Ordered { values } => {
+                if let Some(x) = numeric_value(value) {
+                    values.entry(OrderedKey(x)).or_default().insert(id);
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u32) {
+        match self {
+            Self::Hash { values, .. } => values.values_mut().for_each(|ids| ids.remove(id)),
+            Self::Ordered { values } => values.values_mut().for_each(|ids| ids.remove(id)),
+        }
+    }
+
+    // Ids of the vectors whose value equals `value`, `None` if the index can't
+    // answer the lookup
+    fn lookup_eq(&self, value: &MetadataColumnValue) -> Option<Bitmap> {
+        match (self, value) {
+            (Self::Hash { values, .. }, MetadataColumnValue::StringValue(s)) => Some(
+                values
+                    .get(&HashKey::Str(s.clone()))
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            (Self::Hash { values, has_floats }, MetadataColumnValue::IntValue(i))
+                if !*has_floats =>
+            {
+                Some(values.get(&HashKey::Int(*i)).cloned().unwrap_or_default())
+            }
+            (Self::Ordered { .. }, _) => {
+                let x = numeric_value(value)?;
+                self.lookup_range(Bound::Included(x), Bound::Included(x))
+            }
+            _ => None,
+        }
+    }
+
+    fn lookup_range(&self, lower: Bound<f64>, upper: Bound<f64>) -> Option<Bitmap> {
+        let Self::Ordered { values } = self else {
+            return None;
+        };
+        let mut ids = Bitmap::new();
+        for (_, bitmap) in values.range((lower.map(OrderedKey), upper.map(OrderedKey))) {
+            ids.union_with(bitmap);
+        }
+        Some(ids)
+    }
+}
+
+// Secondary indexes over the metadata of a collection's vectors. Vectors are
+// identified by internal ids, allocated in insertion order, so that the
+// matching sets can be kept as bitmaps
+#[derive(Debug)]
+pub struct PayloadIndexes {
+    configs: Vec<PayloadIndexConfig>,
+    indexes: Vec<PayloadIndex>,
+    keys: Vec<String>,
+    ids: HashMap<String, u32>,
+    // every vector that has a value for each of the indexed columns
+    present: HashMap<String, Bitmap>,
+}
+
+impl PayloadIndexes {
+    pub fn new(configs: Vec<PayloadIndexConfig>) -> Self {
+        let indexes = configs.iter().map(|c| PayloadIndex::new(c.kind)).collect();
+        Self {
+            configs,
+            indexes,
+            keys: Vec::new(),
+            ids: HashMap::new(),
+            present: HashMap::new(),
+        }
+    }
+
+    pub fn configs(&self) -> &[PayloadIndexConfig] {
+        &self.configs
+    }
+
+    // Indexes the metadata of the vector stored under `key` (the vector id's
+    // string form), replacing what was indexed for it before
+    pub fn insert(&mut self, key: &str, metadata: &Metadata) {
+        if self.configs.is_empty() {
+            return;
+        }
+        self.remove(key);
+        let id = match self.ids.get(key) {
+            Some(id) => *id,
+            None => {
+                let id = self.keys.len() as u32;
+                self.keys.push(key.to_string());
+                self.ids.insert(key.to_string(), id);
+                id
+            }
+        };
+        for (config, index) in self.configs.iter().zip(self.indexes.iter_mut()) {
+            if let Some(value) = metadata.get(&config.column) {
+                index.insert(id, value);
+                self.present
+                    .entry(config.column.clone())
+                    .or_default()
+                    .insert(id);
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(id) = self.ids.get(key).copied() {
+            self.indexes.iter_mut().for_each(|index| index.remove(id));
+            self.present.values_mut().for_each(|ids| ids.remove(id));
+        }
+    }
+
+    pub fn internal_id(&self, key: &str) -> Option<u32> {
+        self.ids.get(key).copied()
+    }
+
+    pub fn key(&self, id: u32) -> Option<&str> {
+        self.keys.get(id as usize).map(|key| key.as_str())
+    }
+
+    // Ids of the vectors that may match the filter. The returned set is a
+    // superset of the matches, so the filter still has to be checked against
+    // each candidate's metadata. `None` means the indexes can't narrow the
+    // filter down at all
+    pub fn candidates(&self, filter: &Filter) -> Option<Bitmap> {
+        match filter {
+            Filter::Comparison { column } => intersect_all(
+                column
+                    .iter()
+                    .map(|(name, op)| self.column_candidates(name, op)),
+            ),
+            Filter::Logical(LogicalOperator::And(filters)) => {
+                intersect_all(filters.iter().map(|filter| self.candidates(filter)))
+            }
+            Filter::Logical(LogicalOperator::Or(filters)) => {
+                let mut ids = Bitmap::new();
+                for filter in filters {
+                    ids.union_with(&self.candidates(filter)?);
+                }
+                Some(ids)
+            }
+        }
+    }
+
+    fn column_candidates(&self, column: &str, op: &ComparisonOperator) -> Option<Bitmap> {
+        let indexes: Vec<&PayloadIndex> = self
+            .configs
+            .iter()
+            .zip(&self.indexes)
+            .filter(|(config, _)| config.column == column)
+            .map(|(_, index)| index)
+            .collect();
+
+        let lookup_eq =
+            |value: &MetadataColumnValue| indexes.iter().find_map(|index| index.lookup_eq(value));
+        let lookup_range = |lower: Bound<f64>, upper: Bound<f64>| {
+            indexes
+                .iter()
+                .find_map(|index| index.lookup_range(lower, upper))
+        };
+        let lookup_in = |values: &[MetadataColumnValue]| {
+            let mut ids = Bitmap::new();
+            for value in values {
+                ids.union_with(&lookup_eq(value)?);
+            }
+            Some(ids)
+        };
+        // vectors without the column never match, so `$ne` and `$nin` are
+        // answered relative to the vectors that have it
+        let complement = |ids: Bitmap| {
+            let mut present = self.present.get(column).cloned().unwrap_or_default();
+            present.difference_with(&ids);
+            present
+        };
+
+        match op {
+            ComparisonOperator::Eq(value) => lookup_eq(value),
+            ComparisonOperator::Ne(value) => lookup_eq(value).map(complement),
+            ComparisonOperator::Gt(value) => {
+                lookup_range(Bound::Excluded(numeric_value(value)?), Bound::Unbounded)
+            }
+            ComparisonOperator::Gte(value) => {
+                lookup_range(Bound::Included(numeric_value(value)?), Bound::Unbounded)
+            }
+            ComparisonOperator::Lt(value) => {
+                lookup_range(Bound::Unbounded, Bound::Excluded(numeric_value(value)?))
+            }
+            ComparisonOperator::Lte(value) => {
+                lookup_range(Bound::Unbounded, Bound::Included(numeric_value(value)?))
+            }
+            ComparisonOperator::In(values) => lookup_in(values),
+            ComparisonOperator::Nin(values) => lookup_in(values).map(complement),
+        }
+    }
+}
+
+// Intersection of the sets the indexes could produce, the conditions that
+// can't be answered by an index only widen the result
+fn intersect_all(candidates: impl Iterator<Item = Option<Bitmap>>) -> Option<Bitmap> {
+    candidates.flatten().reduce(|mut acc, ids| {
+        acc.intersect_with(&ids);
+        acc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn indexes() -> PayloadIndexes {
+        let mut indexes = PayloadIndexes::new(vec![
+            PayloadIndexConfig {
+                column: "genre".to_string(),
+                kind: PayloadIndexKind::Hash,
+            },
+            PayloadIndexConfig {
+                column: "year".to_string(),
+                kind: PayloadIndexKind::Ordered,
+            },
+        ]);
+        let rows = [
+            json!({"genre": "scifi", "year": 1965}),
+            json!({"genre": "drama", "year": 1815}),
+            json!({"genre": "scifi", "year": 1984.5}),
+            json!({"genre": "poetry"}),
+        ];
+        for (i, row) in rows.into_iter().enumerate() {
+            let metadata: Metadata = serde_json::from_value(row).unwrap();
+            indexes.insert(&format!("v{}", i), &metadata);
+        }
+        indexes
+    }
+
+    fn candidate_keys(indexes: &PayloadIndexes, filter: serde_json::Value) -> Option<Vec<&str>> {
+        let filter: Filter = serde_json::from_value(filter).unwrap();
+        indexes
+            .candidates(&filter)
+            .map(|ids| ids.iter().map(|id| indexes.key(id).unwrap()).collect())
+    }
+
+    #[test]
+    fn test_bitmap() {
+        let mut a = Bitmap::new();
+        a.insert(1);
+        a.insert(64);
+        a.insert(200);
+        assert!(a.contains(64));
+        assert!(!a.contains(65));
+        assert_eq!(a.len(), 3);
+
+        let mut b = Bitmap::new();
+        b.insert(64);
+        b.insert(3);
+
+        let mut union = a.clone();
+        union.union_with(&b);
+        assert_eq!(union.iter().collect::<Vec<_>>(), vec![1, 3, 64, 200]);
+
+        let mut intersection = a.clone();
+        intersection.intersect_with(&b);
+        assert_eq!(intersection.iter().collect::<Vec<_>>(), vec![64]);
+
+        let mut difference = a.clone();
+        difference.difference_with(&b);
+        assert_eq!(difference.iter().collect::<Vec<_>>(), vec![1, 200]);
+
+        a.remove(200);
+        assert!(!a.contains(200));
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let indexes = indexes();
+
+        assert_eq!(
+            candidate_keys(&indexes, json!({"genre": {"$eq": "scifi"}})),
+            Some(vec!["v0", "v2"])
+        );
+        assert_eq!(
+            candidate_keys(&indexes, json!({"genre": {"$in": ["drama", "poetry"]}})),
+            Some(vec!["v1", "v3"])
+        );
+        assert_eq!(
+            candidate_keys(&indexes, json!({"genre": {"$ne": "scifi"}})),
+            Some(vec!["v1", "v3"])
+        );
+        // range lookups need an ordered index
+        assert_eq!(
+            candidate_keys(&indexes, json!({"genre": {"$gt": "a"}})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ordered_index() {
+        let indexes = indexes();
+
+        assert_eq!(
+            candidate_keys(&indexes, json!({"year": {"$gte": 1965}})),
+            Some(vec!["v0", "v2"])
+        );
+        assert_eq!(
+            candidate_keys(&indexes, json!({"year": {"$lt": 1965}})),
+            Some(vec!["v1"])
+        );
+        assert_eq!(
+            candidate_keys(&indexes, json!({"year": {"$eq": 1984.5}})),
+            Some(vec!["v2"])
+        );
+        assert_eq!(
+            candidate_keys(&indexes, json!({"year": {"$nin": [1965, 1815]}})),
+            Some(vec!["v2"])
+        );
+    }
+
+    #[test]
+    fn test_logical_and_unindexed_columns() {
+        let indexes = indexes();
+
+        assert_eq!(
+            candidate_keys(
+                &indexes,
+                json!({"$and": [{"genre": {"$eq": "scifi"}}, {"year": {"$lt": 1970}}]})
+            ),
+            Some(vec!["v0"])
+        );
+        assert_eq!(
+            candidate_keys(
+                &indexes,
+                json!({"$or": [{"genre": {"$eq": "drama"}}, {"year": {"$gt": 1970}}]})
+            ),
+            Some(vec!["v1", "v2"])
+        );
+        // unindexed conditions only widen the candidates
+        assert_eq!(
+            candidate_keys(
+                &indexes,
+                json!({"genre": {"$eq": "scifi"}, "title": {"$eq": "Dune"}})
+            ),
+            Some(vec!["v0", "v2"])
+        );
+        assert_eq!(
+            candidate_keys(
+                &indexes,
+                json!({"$or": [{"genre": {"$eq": "drama"}}, {"title": {"$eq": "Dune"}}]})
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reinsert_replaces_values() {
+        let mut indexes = indexes();
+        let metadata: Metadata = serde_json::from_value(json!({"genre": "drama"})).unwrap();
+        indexes.insert("v0", &metadata);
+
+        assert_eq!(
+            candidate_keys(&indexes, json!({"genre": {"$eq": "scifi"}})),
+            Some(vec!["v2"])
+        );
+        assert_eq!(
+            candidate_keys(&indexes, json!({"year": {"$gte": 1965}})),
+            Some(vec!["v2"])
+        );
+    }
+}
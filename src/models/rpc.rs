@@ -1,5 +1,9 @@
-use super::types::{MetricResult, VectorId};
-use crate::models::user::{AddUserResp, AuthResp, Statistics, User};
+use super::payload_index::PayloadIndexConfig;
+use super::types::{DistanceMetric, HnswParams, MetricResult, QuantizationMetric, VectorId};
+use super::versioning::{VersionHash, VersionInfo};
+use crate::models::user::{AddUserResp, AuthResp, User};
+use crate::quantization::StorageType;
+use crate::storage::Storage;
 use rayon::iter::WhileSome;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -29,12 +33,35 @@ pub struct VectorANN {
     pub vector: Vec<f32>,
     pub filter: Option<Filter>,
     pub nn_count: Option<i32>,
+    // defaults to "main"
+    #[serde(default)]
+    pub branch: Option<String>,
+    pub version: Option<VersionSelector>,
+    // overrides the collection's `ef_search` for this query
+    #[serde(default)]
+    pub ef_search: Option<usize>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct FetchNeighbors {
     pub vector_db_name: String,
-    pub vector_id: VectorIdValue,
+    #[serde(default)]
+    pub vector_id: Option<VectorIdValue>,
+    #[serde(default)]
+    pub vector_ids: Vec<VectorIdValue>,
+    // defaults to "main"
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub version: Option<VersionSelector>,
+}
+
+// A version of a branch, either by number or by hash
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum VersionSelector {
+    Number(u32),
+    Hash(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -44,6 +71,86 @@ pub struct UpsertVectors {
     pub vectors: Vec<Vector>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransactionVectors {
+    pub vectors: Vec<Vector>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct IndexStatus {
+    pub count_indexed: u32,
+    pub count_unindexed: u32,
+    // the background worker is indexing the collection
+    pub indexing: bool,
+    // embeddings indexed per second during the worker's last run
+    pub throughput: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct CreateIndex {
+    // levels of the new graph, the collection's current number if not given
+    pub max_cache_level: Option<u8>,
+    // parameters of the new graph, the collection's current ones if not given
+    #[serde(default)]
+    pub hnsw_params: Option<HnswParams>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum IndexBuildState {
+    Running,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct IndexBuild {
+    pub state: IndexBuildState,
+    pub max_cache_level: u8,
+    pub hnsw_params: HnswParams,
+    // vectors added to the new graph so far, out of `total`
+    pub indexed: u32,
+    pub total: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Vectors an upsert added, and existing ones it replaced
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InsertStats {
+    pub inserted: u32,
+    pub updated: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DeleteVectors {
+    pub vector_db_name: String,
+    pub vector_ids: Vec<VectorIdValue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransactionVectorIds {
+    pub vector_ids: Vec<VectorIdValue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CreateTransaction {
+    // defaults to "main"
+    #[serde(default)]
+    pub branch: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CreateBranch {
+    pub branch_name: String,
+    // defaults to "main"
+    #[serde(default)]
+    pub parent_branch: Option<String>,
+    // defaults to the latest version of the parent branch
+    #[serde(default)]
+    pub parent_version: Option<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 
 pub struct CreateVectorDb {
@@ -51,6 +158,15 @@ pub struct CreateVectorDb {
     pub dimensions: i32,
     pub max_val: Option<f32>,
     pub min_val: Option<f32>,
+    pub distance_metric: Option<DistanceMetric>,
+    pub quantization: Option<QuantizationMetric>,
+    pub storage_type: Option<StorageType>,
+    pub payload_indexes: Option<Vec<PayloadIndexConfig>>,
+    // number of levels above level 0 of the collection's graph
+    #[serde(default)]
+    pub max_level: Option<u8>,
+    #[serde(default)]
+    pub hnsw_params: Option<HnswParams>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -62,18 +178,87 @@ pub enum RPCResponseBody {
         add_user: AddUserResp,
     },
     RespUpsertVectors {
-        insert_stats: Option<Statistics>,
+        insert_stats: Option<InsertStats>,
     },
     RespVectorKNN {
         knn: Option<Vec<(VectorIdValue, MetricResult)>>,
+        // the metadata of each result, in the same order as `knn`
+        #[serde(default)]
+        metadata: Option<Vec<Option<Metadata>>>,
     },
     RespFetchNeighbors {
         vector: Vector,
+        storage: Storage,
         neighbors: Vec<(VectorIdValue, MetricResult)>,
     },
     RespCreateVectorDb {
         result: bool,
     },
+    RespListCollections {
+        collections: Vec<CollectionInfo>,
+    },
+    RespGetCollection {
+        collection: CollectionInfo,
+    },
+    RespCreateBranch {
+        branch: BranchDetails,
+    },
+    RespListBranches {
+        branches: Vec<BranchDetails>,
+    },
+    RespListVersions {
+        versions: Vec<VersionInfo>,
+    },
+    RespVersionDiff {
+        diff: VersionDiff,
+    },
+    RespRollback {
+        version: VersionHash,
+    },
+    RespDeleteVectors {
+        deleted: u32,
+    },
+    RespIndexStatus {
+        status: IndexStatus,
+    },
+    RespIndexBuild {
+        build: IndexBuild,
+    },
+}
+
+// Vectors of "main" that changed going from version `from` to version `to`
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct VersionDiff {
+    pub from: u32,
+    pub to: u32,
+    pub inserted: Vec<VectorIdValue>,
+    pub updated: Vec<VectorIdValue>,
+    pub deleted: Vec<VectorIdValue>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct BranchDetails {
+    pub name: String,
+    // latest version committed on the branch, the parent version until the
+    // first commit
+    pub head: VersionHash,
+    // version the branch was created from, `None` for "main"
+    pub parent: Option<VersionHash>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct CollectionInfo {
+    pub name: String,
+    pub dimensions: usize,
+    pub max_cache_level: u8,
+    pub quantization_metric: QuantizationMetric,
+    pub distance_metric: DistanceMetric,
+    pub storage_type: StorageType,
+    pub payload_indexes: Vec<PayloadIndexConfig>,
+    pub hnsw_params: HnswParams,
+    pub count_indexed: u32,
+    pub count_unindexed: u32,
+    pub current_version: Option<VersionHash>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -87,6 +272,8 @@ pub enum VectorIdValue {
 pub struct Vector {
     pub id: VectorIdValue,
     pub values: Vec<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
 }
 
 // #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -94,11 +281,13 @@ pub struct Vector {
 //     pub vectors: Vec<Vector>,
 // }
 
+pub type Metadata = HashMap<String, MetadataColumnValue>;
+
 pub type Single = MetadataColumnValue;
 pub type Multiple = Vec<MetadataColumnValue>;
 
 // Define the generic MetadataColumn type
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(untagged)]
 pub enum MetadataColumnValue {
     StringValue(String),
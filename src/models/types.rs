@@ -7,21 +7,26 @@ use crate::distance::{
 use crate::models::common::*;
 use crate::models::identity_collections::*;
 use crate::models::lazy_load::*;
-use crate::models::versioning::VersionHash;
+use crate::models::payload_index::{PayloadIndexConfig, PayloadIndexes};
+use crate::models::rpc::{IndexBuild, Metadata};
+use crate::models::versioning::{VersionHash, VersionHasher};
 use crate::quantization::product::ProductQuantization;
 use crate::quantization::scalar::ScalarQuantization;
 use crate::quantization::{Quantization, StorageType};
 use crate::storage::Storage;
 use arcshift::ArcShift;
 use dashmap::DashMap;
-use lmdb::{Database, Environment};
+use lmdb::{Database, DatabaseFlags, Environment};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fs::*;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::hint::spin_loop;
-use std::path::Path;
-use std::sync::{Arc, OnceLock};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct HNSWLevel(pub u8);
@@ -131,9 +136,21 @@ impl MetricResult {
             MetricResult::DotProductDistance(value) => value.0,
         }
     }
+
+    // gets a value where higher always means closer, so that results of
+    // similarity and distance metrics can be ranked the same way
+    pub fn get_similarity(&self) -> f32 {
+        match self {
+            MetricResult::CosineSimilarity(value) => value.0,
+            MetricResult::CosineDistance(value) => -value.0,
+            MetricResult::EuclideanDistance(value) => -value.0,
+            MetricResult::HammingDistance(value) => -value.0,
+            MetricResult::DotProductDistance(value) => value.0,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum DistanceMetric {
     Cosine,
     Euclidean,
@@ -141,6 +158,22 @@ pub enum DistanceMetric {
     DotProduct,
 }
 
+impl DistanceMetric {
+    // whether `calculate` can compare vectors quantized with the given storage
+    // type, the `SubByte` storages are left out because the scalar quantizer
+    // doesn't compute their magnitudes yet
+    pub fn supports_storage_type(&self, storage_type: StorageType) -> bool {
+        matches!(
+            (self, storage_type),
+            (Self::Cosine, StorageType::UnsignedByte)
+                | (
+                    Self::Euclidean | Self::DotProduct,
+                    StorageType::UnsignedByte | StorageType::HalfPrecisionFP
+                )
+        )
+    }
+}
+
 impl DistanceFunction for DistanceMetric {
     type Item = MetricResult;
     fn calculate(&self, x: &Storage, y: &Storage) -> Result<Self::Item, DistanceError> {
@@ -165,7 +198,7 @@ impl DistanceFunction for DistanceMetric {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum QuantizationMetric {
     Scalar,
     Product(ProductQuantization),
@@ -368,6 +401,171 @@ pub struct MetaDb {
     pub env: Arc<Environment>,
     pub metadata_db: Arc<Database>,
     pub embeddings_db: Arc<Database>,
+    pub payloads_db: Arc<Database>,
+    pub branches_db: Arc<Database>,
+    // every version committed to the collection, keyed by its hash
+    pub versions_db: Arc<Database>,
+    // changes made to the vectors of "main", keyed by `history_key`
+    pub history_db: Arc<Database>,
+    // the same changes keyed by `vector_history_key`, to read the changes
+    // made to one vector
+    pub vector_history_db: Arc<Database>,
+    // offset in `vec_values.0` of the original values of the embedding stored
+    // at some offset in `vec_raw.0`, keyed by the latter
+    pub values_db: Arc<Database>,
+    // location in `prop.data` of the prop of the embedding stored at some
+    // offset in `vec_raw.0`, keyed by the latter
+    pub props_db: Arc<Database>,
+    // changes made to the vectors of the branches other than "main", keyed by
+    // `branch_history_key`
+    pub branch_history_db: Arc<Database>,
+}
+
+impl MetaDb {
+    // Opens (or creates) the LMDB environment of a collection, stored in the
+    // `_mdb` subdirectory of the collection's data directory
+    pub fn from_path(collection_path: &Path) -> Result<Self, WaCustomError> {
+        let path = collection_path.join("_mdb");
+
+        create_dir_all(&path).map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+        let env = Environment::new()
+            .set_max_dbs(10)
+            .set_map_size(10485760) // Set the maximum size of the database to 10MB
+            .open(&path)
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let metadata_db = env
+            .create_db(Some("metadata"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let embeddings_db = env
+            .create_db(Some("embeddings"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let payloads_db = env
+            .create_db(Some("payloads"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let branches_db = env
+            .create_db(Some("branches"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let versions_db = env
+            .create_db(Some("versions"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let history_db = env
+            .create_db(Some("history"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let vector_history_db = env
+            .create_db(Some("vector_history"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let values_db = env
+            .create_db(Some("values"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let props_db = env
+            .create_db(Some("props"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        let branch_history_db = env
+            .create_db(Some("branch_history"), DatabaseFlags::empty())
+            .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+        Ok(Self {
+            env: Arc::new(env),
+            metadata_db: Arc::new(metadata_db),
+            embeddings_db: Arc::new(embeddings_db),
+            payloads_db: Arc::new(payloads_db),
+            branches_db: Arc::new(branches_db),
+            versions_db: Arc::new(versions_db),
+            history_db: Arc::new(history_db),
+            vector_history_db: Arc::new(vector_history_db),
+            values_db: Arc::new(values_db),
+            props_db: Arc::new(props_db),
+            branch_history_db: Arc::new(branch_history_db),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum StagedOperation {
+    Upsert {
+        id: VectorId,
+        values: Vec<f32>,
+        metadata: Option<Metadata>,
+    },
+    // same as upsert, but the vector must already exist
+    Update {
+        id: VectorId,
+        values: Vec<f32>,
+        metadata: Option<Metadata>,
+    },
+    Delete {
+        id: VectorId,
+    },
+}
+
+impl StagedOperation {
+    pub fn vector_id(&self) -> &VectorId {
+        match self {
+            Self::Upsert { id, .. } | Self::Update { id, .. } | Self::Delete { id } => id,
+        }
+    }
+}
+
+// A change made to a vector in some version of the collection. `offset` is the
+// new embedding's offset in `vec_raw.0`, `None` if the vector was deleted.
+// `metadata` is `None` when the metadata was left as it was
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VectorChange {
+    pub offset: Option<u32>,
+    pub metadata: Option<Metadata>,
+}
+
+// A change to a vector, applied along with the other changes of its version.
+// `Store` makes the embedding at `offset` the vector's current one, `appended`
+// if it was just appended to `vec_raw.0` and still has to be indexed
+#[derive(Debug, Clone)]
+pub enum VectorWrite {
+    Store {
+        id: VectorId,
+        offset: u32,
+        values_offset: Option<u32>,
+        metadata: Option<Metadata>,
+        appended: bool,
+    },
+    Delete {
+        id: VectorId,
+    },
+}
+
+// A vector as it was in some version of the collection, rebuilt from the
+// changes recorded up to that version
+#[derive(Debug, Clone)]
+pub struct VectorSnapshot {
+    pub offset: u32,
+    pub metadata: Option<Metadata>,
+}
+
+// A vector as it was in two versions, None in those it didn't exist in
+pub type VectorVersions = (Option<VectorSnapshot>, Option<VectorSnapshot>);
+
+// Background indexing of the embeddings that were stored but aren't in the
+// graph yet
+#[derive(Debug, Default)]
+pub struct IndexingState {
+    // a worker is indexing the collection
+    pub running: bool,
+    // more embeddings were stored since the worker started its last run
+    pub pending: bool,
+    // embeddings indexed per second during the last run
+    pub throughput: f64,
+    // the last explicit build of a new graph, and whether it was asked to stop
+    pub build: Option<IndexBuild>,
+    pub cancel_build: bool,
 }
 
 #[derive(Clone)]
@@ -375,16 +573,44 @@ pub struct VectorStore {
     pub exec_queue_nodes: ExecQueueUpdate,
     pub max_cache_level: u8,
     pub database_name: String,
+    pub collection_path: PathBuf,
     pub root_vec: LazyItemRef<MergedNode>,
     pub levels_prob: Arc<Vec<(f64, i32)>>,
+    pub dimensions: usize,
     pub quant_dim: usize,
     pub prop_file: Arc<File>,
     pub lmdb: MetaDb,
     pub current_version: ArcShift<Option<VersionHash>>,
     pub current_open_transaction: ArcShift<Option<VersionHash>>,
+    // heads of the collection's branches, persisted in `lmdb.branches_db`
+    pub version_hasher: Arc<Mutex<VersionHasher>>,
     pub quantization_metric: Arc<QuantizationMetric>,
     pub distance_metric: Arc<DistanceMetric>,
     pub storage_type: StorageType,
+    pub payload_indexes: Arc<RwLock<PayloadIndexes>>,
+    // operations of the open transaction, applied when it's committed
+    pub staged_operations: STM<Vec<StagedOperation>>,
+    // held while operations are staged, committed or dropped, so that each
+    // request's operations are checked against everything staged before them
+    pub staging_lock: Arc<Mutex<()>>,
+    // deleted vectors, searches skip their nodes if they're still reachable
+    pub tombstones: Arc<RwLock<HashSet<VectorId>>>,
+    // nodes of each indexed vector, from its top level down to level 0
+    pub vector_nodes: Arc<RwLock<HashMap<VectorId, Vec<LazyItem<MergedNode>>>>>,
+    // held by an implicit upsert while it runs, or by a transaction while it's
+    // open, so that writes to the collection happen one at a time
+    pub write_lock: Arc<Semaphore>,
+    pub transaction_permit: Arc<Mutex<Option<OwnedSemaphorePermit>>>,
+    // when the open transaction was created or last staged operations, it's
+    // aborted once it's been idle for too long
+    pub transaction_activity: Arc<Mutex<Instant>>,
+    // held while an embedding is appended to `vec_raw.0` and its offset is
+    // stored, so that readers never see a partially written embedding
+    pub append_lock: Arc<Mutex<()>>,
+    // held while embeddings are indexed, so that they're only indexed once
+    pub index_lock: Arc<Mutex<()>>,
+    pub indexing: Arc<Mutex<IndexingState>>,
+    pub hnsw_params: HnswParams,
 }
 
 impl VectorStore {
@@ -392,8 +618,10 @@ impl VectorStore {
         exec_queue_nodes: ExecQueueUpdate,
         max_cache_level: u8,
         database_name: String,
+        collection_path: PathBuf,
         root_vec: LazyItemRef<MergedNode>,
         levels_prob: Arc<Vec<(f64, i32)>>,
+        dimensions: usize,
         quant_dim: usize,
         prop_file: Arc<File>,
         lmdb: MetaDb,
@@ -401,21 +629,38 @@ impl VectorStore {
         quantization_metric: Arc<QuantizationMetric>,
         distance_metric: Arc<DistanceMetric>,
         storage_type: StorageType,
+        payload_indexes: Vec<PayloadIndexConfig>,
+        hnsw_params: HnswParams,
     ) -> Self {
         VectorStore {
             exec_queue_nodes,
             max_cache_level,
             database_name,
+            collection_path,
             root_vec,
             levels_prob,
+            dimensions,
             quant_dim,
             prop_file,
             lmdb,
             current_version,
             current_open_transaction: ArcShift::new(None),
+            version_hasher: Arc::new(Mutex::new(VersionHasher::new())),
             quantization_metric,
             distance_metric,
             storage_type,
+            payload_indexes: Arc::new(RwLock::new(PayloadIndexes::new(payload_indexes))),
+            staged_operations: STM::new(Vec::new(), 1, true),
+            staging_lock: Arc::new(Mutex::new(())),
+            tombstones: Arc::new(RwLock::new(HashSet::new())),
+            vector_nodes: Arc::new(RwLock::new(HashMap::new())),
+            write_lock: Arc::new(Semaphore::new(1)),
+            transaction_permit: Arc::new(Mutex::new(None)),
+            transaction_activity: Arc::new(Mutex::new(Instant::now())),
+            append_lock: Arc::new(Mutex::new(())),
+            index_lock: Arc::new(Mutex::new(())),
+            indexing: Arc::new(Mutex::new(IndexingState::default())),
+            hnsw_params,
         }
     }
     // Get method
@@ -430,6 +675,72 @@ impl VectorStore {
         arc.update(new_version);
     }
 }
+// Persisted definition of a collection, used to rebuild its `VectorStore` on startup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionConfig {
+    pub name: String,
+    pub dimensions: usize,
+    pub max_cache_level: u8,
+    pub quantization_metric: QuantizationMetric,
+    pub distance_metric: DistanceMetric,
+    pub storage_type: StorageType,
+    pub payload_indexes: Vec<PayloadIndexConfig>,
+    pub hnsw_params: HnswParams,
+}
+
+// Construction and search parameters of a collection's HNSW graph. Fields
+// missing from a request get the defaults
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct HnswParams {
+    // most neighbors a node keeps per level
+    pub m: usize,
+    // candidates kept per level while inserting a node
+    pub ef_construction: usize,
+    // candidates kept per level while searching, unless the query overrides
+    // it. At least as many as the number of requested results are kept
+    pub ef_search: usize,
+    // each level has about this many times fewer nodes than the one below
+    pub level_multiplier: f64,
+    // how a node's neighbors are picked among the candidates found for it
+    pub neighbor_selection: NeighborSelection,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum NeighborSelection {
+    // the closest candidates
+    #[default]
+    Simple,
+    // candidates closer to the node than to any neighbor picked before them,
+    // so that neighbors lie in different directions. With `keep_pruned`, the
+    // room left is filled with the closest of the skipped candidates
+    Heuristic {
+        keep_pruned: bool,
+    },
+}
+
+impl Default for HnswParams {
+    fn default() -> Self {
+        HnswParams {
+            m: 20,
+            ef_construction: 100,
+            ef_search: 50,
+            level_multiplier: 10.0,
+            neighbor_selection: NeighborSelection::default(),
+        }
+    }
+}
+
+// Highest level a collection's graph can be built with. Each level adds a node
+// to the root chain, and a level to be searched on every query and insertion
+pub const MAX_CACHE_LEVEL: u8 = 16;
+
+impl HnswParams {
+    pub fn is_valid(&self) -> bool {
+        self.m > 0 && self.ef_construction > 0 && self.ef_search > 0 && self.level_multiplier > 1.0
+    }
+}
+
 #[derive(Debug, Clone, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize, PartialEq)]
 pub struct VectorEmbedding {
     pub raw_vec: Arc<Storage>,
@@ -443,34 +754,57 @@ type UserDataCache = DashMap<String, (String, i32, i32, std::time::SystemTime, V
 pub struct AppEnv {
     pub user_data_cache: UserDataCache,
     pub vector_store_map: VectorStoreMap,
+    pub data_dir: PathBuf,
     pub persist: Arc<Environment>,
+    pub collections_db: Arc<Database>,
+}
+
+impl AppEnv {
+    // Each collection gets its own directory under `data_dir`
+    pub fn collection_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(name)
+    }
 }
 
 static AIN_ENV: OnceLock<Result<Arc<AppEnv>, WaCustomError>> = OnceLock::new();
 
-pub fn get_app_env() -> Result<Arc<AppEnv>, WaCustomError> {
+pub fn init_app_env(data_dir: &Path) -> Result<Arc<AppEnv>, WaCustomError> {
     AIN_ENV
         .get_or_init(|| {
-            let path = Path::new("./_mdb"); // TODO: prefix the customer & database name
+            let path = data_dir.join("_mdb");
 
             // Ensure the directory exists
             create_dir_all(&path).map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
             // Initialize the environment
             let env = Environment::new()
-                .set_max_dbs(2)
+                .set_max_dbs(1)
                 .set_map_size(10485760) // Set the maximum size of the database to 10MB
                 .open(&path)
                 .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
 
+            let collections_db = env
+                .create_db(Some("collections"), DatabaseFlags::empty())
+                .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
             Ok(Arc::new(AppEnv {
                 user_data_cache: DashMap::new(),
                 vector_store_map: DashMap::new(),
+                data_dir: data_dir.to_path_buf(),
                 persist: Arc::new(env),
+                collections_db: Arc::new(collections_db),
             }))
         })
         .clone()
 }
 
+pub fn get_app_env() -> Result<Arc<AppEnv>, WaCustomError> {
+    AIN_ENV.get().cloned().unwrap_or_else(|| {
+        Err(WaCustomError::DatabaseError(
+            "App env is not initialized".to_string(),
+        ))
+    })
+}
+
 #[derive(Clone)]
 pub struct STM<T: 'static> {
     arcshift: ArcShift<T>,
@@ -535,3 +869,55 @@ where
         Ok(updated)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_missing_hnsw_params_get_defaults() {
+        let params: HnswParams = serde_json::from_value(json!({"m": 32})).unwrap();
+        assert_eq!(params.m, 32);
+        assert_eq!(
+            params.ef_construction,
+            HnswParams::default().ef_construction
+        );
+        assert_eq!(
+            params.level_multiplier,
+            HnswParams::default().level_multiplier
+        );
+    }
+
+    #[test]
+    fn test_hnsw_params_validation() {
+        assert!(HnswParams::default().is_valid());
+
+        let params = HnswParams {
+            level_multiplier: 1.0,
+            ..Default::default()
+        };
+        assert!(!params.is_valid());
+
+        let params = HnswParams {
+            ef_search: 0,
+            ..Default::default()
+        };
+        assert!(!params.is_valid());
+    }
+
+    #[test]
+    fn test_neighbor_selection_serialization() {
+        let params: HnswParams = serde_json::from_value(json!({
+            "neighbor_selection": {"Heuristic": {"keep_pruned": true}}
+        }))
+        .unwrap();
+        assert_eq!(
+            params.neighbor_selection,
+            NeighborSelection::Heuristic { keep_pruned: true }
+        );
+
+        let params: HnswParams = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(params.neighbor_selection, NeighborSelection::Simple);
+    }
+}
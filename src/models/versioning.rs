@@ -4,7 +4,14 @@ use serde::{Deserialize, Serialize};
 use siphasher::sip::SipHasher24;
 
 #[derive(
-    Serialize, Deserialize, Clone, Debug, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Debug,
+    PartialEq,
+    rkyv::Archive,
+    rkyv::Serialize,
+    rkyv::Deserialize,
 )]
 pub struct VersionHash {
     pub branch: String,
@@ -12,16 +19,57 @@ pub struct VersionHash {
     pub hash: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct OperationCounts {
+    pub inserted: u32,
+    pub updated: u32,
+    pub deleted: u32,
+}
+
+impl OperationCounts {
+    pub fn add(&mut self, other: &OperationCounts) {
+        self.inserted += other.inserted;
+        self.updated += other.updated;
+        self.deleted += other.deleted;
+    }
+}
+
+// A version as it's recorded in the versions db. `parent` is the hash of the
+// previous version of the branch, or of the version the branch was created
+// from. `timestamp` is in seconds since the epoch
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct VersionInfo {
+    #[serde(flatten)]
+    pub version_hash: VersionHash,
+    pub parent: Option<String>,
+    pub timestamp: u64,
+    pub operations: OperationCounts,
+}
+
+// `parent_*` point at the version the branch was created from, they are
+// empty for "main"
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BranchInfo {
-    current_hash: String,
-    parent_branch: String,
-    parent_hash: String,
-    parent_version: u32,
+    pub current_hash: String,
+    pub current_version: u32,
+    pub parent_branch: String,
+    pub parent_hash: String,
+    pub parent_version: u32,
+}
+
+impl BranchInfo {
+    pub fn head(&self, branch: &str) -> VersionHash {
+        VersionHash {
+            branch: branch.to_string(),
+            version: self.current_version,
+            hash: self.current_hash.clone(),
+        }
+    }
 }
 
 use std::{collections::HashMap, hash::Hasher};
 
+#[derive(Clone)]
 pub struct VersionHasher {
     branches: HashMap<String, BranchInfo>,
 }
@@ -33,6 +81,7 @@ impl VersionHasher {
             "main".to_string(),
             BranchInfo {
                 current_hash: String::new(),
+                current_version: 0,
                 parent_branch: String::new(),
                 parent_hash: String::new(),
                 parent_version: 0,
@@ -41,6 +90,42 @@ impl VersionHasher {
         Self { branches }
     }
 
+    // Restores the hasher from the persisted branches, starting from a fresh
+    // "main" if there are none
+    pub fn from_branches(branches: HashMap<String, BranchInfo>) -> Self {
+        if branches.is_empty() {
+            return Self::new();
+        }
+        Self { branches }
+    }
+
+    pub fn branches(&self) -> &HashMap<String, BranchInfo> {
+        &self.branches
+    }
+
+    pub fn branch(&self, branch: &str) -> Option<&BranchInfo> {
+        self.branches.get(branch)
+    }
+
+    // Starts a new branch at `parent`, its first version will be
+    // `parent.version + 1`
+    pub fn create_branch(&mut self, branch: &str, parent: &VersionHash) -> Option<&BranchInfo> {
+        if self.branches.contains_key(branch) {
+            return None;
+        }
+        self.branches.insert(
+            branch.to_string(),
+            BranchInfo {
+                current_hash: parent.hash.clone(),
+                current_version: parent.version,
+                parent_branch: parent.branch.clone(),
+                parent_hash: parent.hash.clone(),
+                parent_version: parent.version,
+            },
+        );
+        self.branches.get(branch)
+    }
+
     pub fn generate_hash(
         &mut self,
         branch: &str,
@@ -48,11 +133,12 @@ impl VersionHasher {
         parent_branch: Option<&str>,
         parent_version: Option<u32>,
     ) -> VersionHash {
-        let (parent_hash, parent_branch, parent_version) =
+        let (current_hash, parent_branch, parent_hash, parent_version) =
             if let Some(branch_info) = self.branches.get(branch) {
                 (
                     branch_info.current_hash.clone(),
                     branch_info.parent_branch.clone(),
+                    branch_info.parent_hash.clone(),
                     branch_info.parent_version,
                 )
             } else {
@@ -61,11 +147,12 @@ impl VersionHasher {
                 (
                     parent_info.current_hash.clone(),
                     parent_branch,
+                    parent_info.current_hash.clone(),
                     parent_version.unwrap_or(0),
                 )
             };
 
-        let input = format!("{}{}{}", parent_hash, branch, version);
+        let input = format!("{}{}{}", current_hash, branch, version);
 
         let mut hasher = SipHasher24::new();
         hasher.write(input.as_bytes());
@@ -77,6 +164,7 @@ impl VersionHasher {
             branch.to_string(),
             BranchInfo {
                 current_hash: base58_str.clone(),
+                current_version: version,
                 parent_branch,
                 parent_hash,
                 parent_version,
@@ -90,3 +178,65 @@ impl VersionHasher {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restored_hasher_continues_the_chain() {
+        let mut hasher = VersionHasher::new();
+        hasher.generate_hash("main", 0, None, None);
+        hasher.generate_hash("main", 1, None, None);
+
+        let mut restored = VersionHasher::from_branches(hasher.branches().clone());
+        assert_eq!(
+            restored.generate_hash("main", 2, None, None),
+            hasher.generate_hash("main", 2, None, None)
+        );
+    }
+
+    #[test]
+    fn test_version_info_roundtrip() {
+        let mut hasher = VersionHasher::new();
+        let parent = hasher.generate_hash("main", 2, None, None);
+        let version_info = VersionInfo {
+            version_hash: hasher.generate_hash("main", 3, None, None),
+            parent: Some(parent.hash),
+            timestamp: 1_700_000_000,
+            operations: OperationCounts {
+                inserted: 2,
+                updated: 1,
+                deleted: 0,
+            },
+        };
+        let serialized = serde_cbor::to_vec(&version_info).unwrap();
+
+        let restored: VersionInfo = serde_cbor::from_slice(&serialized).unwrap();
+        assert_eq!(restored, version_info);
+    }
+
+    #[test]
+    fn test_branch_starts_at_parent_version() {
+        let mut hasher = VersionHasher::new();
+        hasher.generate_hash("main", 0, None, None);
+        let parent = hasher.generate_hash("main", 1, None, None);
+
+        assert!(hasher.create_branch("experiment", &parent).is_some());
+        assert!(hasher.create_branch("experiment", &parent).is_none());
+
+        let head = hasher.branch("experiment").unwrap().head("experiment");
+        assert_eq!(head.version, 1);
+        assert_eq!(head.hash, parent.hash);
+
+        let version = hasher.generate_hash("experiment", 2, None, None);
+        let info = hasher.branch("experiment").unwrap();
+        assert_eq!(info.current_hash, version.hash);
+        assert_eq!(info.parent_branch, "main");
+        assert_eq!(info.parent_hash, parent.hash);
+        assert_eq!(info.parent_version, 1);
+
+        // committing on the branch leaves main where it was
+        assert_eq!(hasher.branch("main").unwrap().current_hash, parent.hash);
+    }
+}
@@ -0,0 +1,267 @@
+use super::common::WaCustomError;
+use super::types::StagedOperation;
+use super::versioning::VersionHash;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::{Deserialize, Serialize};
+use std::fs::{metadata, remove_file, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Per-collection write-ahead log of the open transaction. Records are appended
+// as they happen and the log is fsynced when the transaction commits, so a
+// committed transaction can be applied again if the server goes down midway.
+// The log is removed once the transaction is applied or aborted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WalRecord {
+    Begin { transaction: VersionHash },
+    Operation { operation: StagedOperation },
+    Commit,
+}
+
+pub fn wal_path(collection_path: &Path) -> PathBuf {
+    collection_path.join("txn.wal")
+}
+
+pub fn write_wal_record<W: Write>(writer: &mut W, record: &WalRecord) -> Result<(), WaCustomError> {
+    let serialized =
+        serde_cbor::to_vec(record).map_err(|e| WaCustomError::SerializationError(e.to_string()))?;
+
+    writer
+        .write_u32::<LittleEndian>(serialized.len() as u32)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    writer
+        .write_all(&serialized)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    Ok(())
+}
+
+// Reads the records up to the end of the log. A record that was only partially
+// written when the server went down ends the log
+pub fn read_wal_records<R: Read>(reader: &mut R) -> Result<Vec<WalRecord>, WaCustomError> {
+    let mut records = Vec::new();
+
+    loop {
+        let len = match reader.read_u32::<LittleEndian>() {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+        };
+
+        let mut buf = vec![0; len as usize];
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+        }
+
+        match serde_cbor::from_slice(&buf) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+    }
+
+    Ok(records)
+}
+
+pub fn append_wal_records(
+    path: &Path,
+    records: &[WalRecord],
+    sync: bool,
+) -> Result<(), WaCustomError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let mut buf = Vec::new();
+    for record in records {
+        write_wal_record(&mut buf, record)?;
+    }
+    file.write_all(&buf)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    if sync {
+        file.sync_data()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
+pub fn read_wal(path: &Path) -> Result<Vec<WalRecord>, WaCustomError> {
+    let file = match OpenOptions::new().read(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+    };
+
+    read_wal_records(&mut BufReader::new(file))
+}
+
+pub fn clear_wal(path: &Path) -> Result<(), WaCustomError> {
+    match remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(WaCustomError::FsError(e.to_string())),
+    }
+}
+
+// Size of the log in bytes, 0 if there's none
+pub fn wal_len(path: &Path) -> Result<u64, WaCustomError> {
+    match metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(WaCustomError::FsError(e.to_string())),
+    }
+}
+
+// Drops the records appended after the log was `len` bytes long
+pub fn truncate_wal(path: &Path, len: u64) -> Result<(), WaCustomError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    file.set_len(len)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    file.sync_data()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))
+}
+
+// Finds the transaction that was committed but possibly not applied, along
+// with its operations. Transactions without a commit record are discarded
+pub fn committed_transaction(
+    records: Vec<WalRecord>,
+) -> Option<(VersionHash, Vec<StagedOperation>)> {
+    let mut current: Option<(VersionHash, Vec<StagedOperation>)> = None;
+    let mut committed = None;
+
+    for record in records {
+        match record {
+            WalRecord::Begin { transaction } => current = Some((transaction, Vec::new())),
+            WalRecord::Operation { operation } => {
+                if let Some((_, operations)) = current.as_mut() {
+                    operations.push(operation);
+                }
+            }
+            WalRecord::Commit => {
+                if let Some(transaction) = current.take() {
+                    committed = Some(transaction);
+                }
+            }
+        }
+    }
+
+    committed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::models::types::VectorId;
+    use crate::models::versioning::VersionHasher;
+
+    fn transaction(version: u32) -> VersionHash {
+        VersionHasher::new().generate_hash("main", version, None, None)
+    }
+
+    fn delete(id: i32) -> WalRecord {
+        WalRecord::Operation {
+            operation: StagedOperation::Delete {
+                id: VectorId::Int(id),
+            },
+        }
+    }
+
+    fn write_records(records: &[WalRecord]) -> Vec<u8> {
+        let mut writer = Cursor::new(Vec::new());
+        for record in records {
+            write_wal_record(&mut writer, record).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_committed_transaction_is_replayed() {
+        let bytes = write_records(&[
+            WalRecord::Begin {
+                transaction: transaction(1),
+            },
+            delete(1),
+            delete(2),
+            WalRecord::Commit,
+        ]);
+
+        let records = read_wal_records(&mut Cursor::new(bytes)).unwrap();
+        let (txn, operations) = committed_transaction(records).unwrap();
+
+        assert_eq!(txn, transaction(1));
+        assert_eq!(operations.len(), 2);
+        assert_eq!(operations[1].vector_id(), &VectorId::Int(2));
+    }
+
+    #[test]
+    fn test_uncommitted_transactions_are_discarded() {
+        let bytes = write_records(&[
+            WalRecord::Begin {
+                transaction: transaction(1),
+            },
+            delete(1),
+            WalRecord::Begin {
+                transaction: transaction(1),
+            },
+            delete(2),
+        ]);
+
+        let records = read_wal_records(&mut Cursor::new(bytes)).unwrap();
+        assert!(committed_transaction(records).is_none());
+    }
+
+    #[test]
+    fn test_truncated_commit_reopens_the_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = wal_path(dir.path());
+        append_wal_records(
+            &path,
+            &[
+                WalRecord::Begin {
+                    transaction: transaction(1),
+                },
+                delete(1),
+            ],
+            false,
+        )
+        .unwrap();
+
+        let len = wal_len(&path).unwrap();
+        append_wal_records(&path, &[WalRecord::Commit], true).unwrap();
+        assert!(committed_transaction(read_wal(&path).unwrap()).is_some());
+
+        truncate_wal(&path, len).unwrap();
+        let records = read_wal(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(committed_transaction(records).is_none());
+    }
+
+    #[test]
+    fn test_torn_record_ends_the_log() {
+        let mut bytes = write_records(&[
+            WalRecord::Begin {
+                transaction: transaction(1),
+            },
+            delete(1),
+            WalRecord::Commit,
+        ]);
+        // the commit record was only partially written
+        bytes.truncate(bytes.len() - 1);
+
+        let records = read_wal_records(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(committed_transaction(records).is_none());
+    }
+}
@@ -2,13 +2,14 @@ pub mod product;
 pub mod scalar;
 
 use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
 
 pub trait Quantization: std::fmt::Debug + Send + Sync {
     fn quantize(&self, vector: &[f32], storage_type: StorageType) -> Storage;
     fn train(&mut self, vectors: &[Vec<f32>]) -> Result<(), QuantizationError>;
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum StorageType {
     UnsignedByte,
     SubByte(u8),
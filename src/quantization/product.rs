@@ -1,12 +1,13 @@
 use super::{Quantization, QuantizationError, StorageType};
 use crate::storage::Storage;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ProductQuantization {
     centroids: Option<Centroid>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Centroid {
     pub number_of_centroids: u16,
     pub centroids: Vec<u16>,
@@ -2,19 +2,27 @@ use crate::distance::DistanceFunction;
 use crate::models::common::*;
 use crate::models::custom_buffered_writer::CustomBufferedWriter;
 use crate::models::file_persist::*;
+use crate::models::filter::SearchFilter;
+use crate::models::identity_collections::Identifiable;
 use crate::models::lazy_load::*;
+use crate::models::meta_persist::{
+    put_branch_vector_change, put_current_version, put_vector_change, retrieve_branch_vector_as_of,
+    retrieve_vector_metadata,
+};
+use crate::models::rpc::{Filter, Metadata};
 use crate::models::types::*;
+use crate::models::versioning::{OperationCounts, VersionHash};
 use crate::storage::Storage;
 use arcshift::ArcShift;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use lmdb::Transaction;
 use lmdb::WriteFlags;
+use lmdb::{Cursor, Transaction};
+use rayon::iter::IndexedParallelIterator;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
-use smallvec::SmallVec;
 use std::array::TryFromSliceError;
-use std::collections::HashSet;
-use std::fs::File;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Seek;
@@ -22,90 +30,603 @@ use std::io::SeekFrom;
 use std::io::Write;
 use std::sync::Arc;
 
+// How many more candidates are explored per level when the search is
+// filtered, as some of them are expected to be rejected by the filter
+const FILTERED_CANDIDATES_FACTOR: usize = 4;
+
+// Searches the graph from the top of the root chain down to level 0, with a
+// best-first search of each level. Nodes that don't match the filter are still
+// used to navigate the graph, but are left out of the returned results
 pub fn ann_search(
     vec_store: Arc<VectorStore>,
     vector_emb: VectorEmbedding,
-    cur_entry: LazyItem<MergedNode>,
-    cur_level: i8,
+    k: usize,
+    ef_search: usize,
+    filter: Option<&SearchFilter>,
 ) -> Result<Option<Vec<(LazyItem<MergedNode>, MetricResult)>>, WaCustomError> {
-    if cur_level == -1 {
-        return Ok(Some(vec![]));
-    }
+    // the level 0 root may be among the nearest nodes, but isn't returned
+    let ef = match filter {
+        Some(_) => k.max(ef_search) * FILTERED_CANDIDATES_FACTOR,
+        None => k.max(ef_search),
+    } + 1;
 
     let fvec = vector_emb.raw_vec.clone();
-    let mut skipm = HashSet::new();
-    skipm.insert(vector_emb.hash_vec.clone());
+    let (entry, level) = top_root(&vec_store)?;
+    let dist = vec_store
+        .distance_metric
+        .calculate(&fvec, &*node_value(&entry)?)?;
+
+    let mut entries = vec![(entry, dist)];
+    let mut nearest = Vec::new();
+    for level in (0..=level).rev() {
+        // only the closest node is needed to enter the level below
+        let level_ef = if level == 0 { ef } else { 1 };
+        nearest = search_layer(vec_store.clone(), &fvec, entries, level_ef, None)?;
+        entries = child_nodes(&nearest);
+    }
 
-    let mut cur_node_arc = match cur_entry.clone() {
-        LazyItem::Valid {
-            data: Some(node), ..
-        } => node,
-        LazyItem::Valid {
-            data: None,
-            mut file_index,
-            ..
-        } => {
-            if let Some(file_index) = file_index.get() {
-                return Err(WaCustomError::LazyLoadingError(format!(
-                    "Node at offset {} needs to be loaded",
-                    file_index
-                )));
-            } else {
-                return Err(WaCustomError::NodeError(
-                    "Current entry is null".to_string(),
-                ));
+    let mut matching = Vec::new();
+    for (node, dist) in nearest {
+        if is_tombstoned(vec_store.clone(), &node) || is_replaced(&vec_store, &node) {
+            continue;
+        }
+        if let Some(filter) = filter {
+            if !node_matches_filter(vec_store.clone(), &node, filter)? {
+                continue;
+            }
+        }
+        matching.push((node, dist));
+    }
+
+    Ok(Some(matching))
+}
+
+// A node reached by a search, ordered by its similarity to the searched vector
+#[derive(Clone)]
+struct SearchCandidate {
+    similarity: f32,
+    node: LazyItem<MergedNode>,
+    dist: MetricResult,
+}
+
+impl PartialEq for SearchCandidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.similarity.total_cmp(&other.similarity) == Ordering::Equal
+    }
+}
+
+impl Eq for SearchCandidate {}
+
+impl PartialOrd for SearchCandidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SearchCandidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.similarity.total_cmp(&other.similarity)
+    }
+}
+
+// Best-first search of a single level of the graph. Candidates are expanded
+// closest first, and the search ends once the closest unexpanded candidate is
+// further than the furthest of the `ef` nearest nodes found so far. Returns
+// the nearest nodes, closest first. The node of `skip` is never returned
+fn search_layer(
+    vec_store: Arc<VectorStore>,
+    fvec: &Storage,
+    entries: Vec<(LazyItem<MergedNode>, MetricResult)>,
+    ef: usize,
+    skip: Option<&VectorId>,
+) -> Result<Vec<(LazyItem<MergedNode>, MetricResult)>, WaCustomError> {
+    let mut visited = HashSet::new();
+    // closest candidate on top
+    let mut candidates = BinaryHeap::new();
+    // furthest of the nearest nodes on top
+    let mut nearest = BinaryHeap::new();
+
+    for (node, dist) in entries {
+        let Some(mut node_arc) = node.get_data() else {
+            continue;
+        };
+        let Some(id) = get_vector_id_from_node(node_arc.get()) else {
+            continue;
+        };
+        if !visited.insert(id.clone()) {
+            continue;
+        }
+
+        let candidate = SearchCandidate {
+            similarity: dist.get_similarity(),
+            node,
+            dist,
+        };
+        candidates.push(candidate.clone());
+        if skip != Some(&id) {
+            nearest.push(Reverse(candidate));
+        }
+    }
+
+    while let Some(candidate) = candidates.pop() {
+        if nearest.len() >= ef {
+            if let Some(Reverse(furthest)) = nearest.peek() {
+                if candidate.similarity < furthest.similarity {
+                    break;
+                }
+            }
+        }
+
+        let Some(mut node_arc) = candidate.node.get_data() else {
+            continue;
+        };
+
+        // edges to removed nodes that weren't unlinked because they only went
+        // one way, purged as they're found
+        let mut dangling = HashSet::new();
+        for nbr in node_arc.get().neighbors.iter() {
+            if is_tombstoned(vec_store.clone(), &nbr.1) || is_replaced(&vec_store, &nbr.1) {
+                dangling.insert(nbr.get_id());
+                continue;
+            }
+            let Some(mut nbr_arc) = nbr.1.get_data() else {
+                continue;
+            };
+            let mut prop_arc = nbr_arc.get().prop.clone();
+            let node_prop = match prop_arc.get() {
+                PropState::Ready(prop) => prop.clone(),
+                PropState::Pending(loc) => {
+                    return Err(WaCustomError::NodeError(format!(
+                        "Neighbor prop is in pending state at loc: {:?}",
+                        loc
+                    )))
+                }
+            };
+
+            if !visited.insert(node_prop.id.clone()) {
+                continue;
+            }
+
+            let dist = vec_store
+                .distance_metric
+                .calculate(fvec, &node_prop.value)?;
+            let similarity = dist.get_similarity();
+
+            let is_closer = match nearest.peek() {
+                Some(Reverse(furthest)) => similarity > furthest.similarity,
+                None => true,
+            };
+            if nearest.len() < ef || is_closer {
+                let candidate = SearchCandidate {
+                    similarity,
+                    node: nbr.1,
+                    dist,
+                };
+                candidates.push(candidate.clone());
+                if skip != Some(&node_prop.id) {
+                    nearest.push(Reverse(candidate));
+                    if nearest.len() > ef {
+                        nearest.pop();
+                    }
+                }
             }
         }
-        _ => {
+
+        if !dangling.is_empty() {
+            node_arc
+                .get()
+                .neighbors
+                .retain(|nbr| !dangling.contains(&nbr.get_id()));
+        }
+    }
+
+    Ok(nearest
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(candidate)| (candidate.node, candidate.dist))
+        .collect())
+}
+
+// The topmost node of the root chain that's loaded, along with its level
+fn top_root(vec_store: &VectorStore) -> Result<(LazyItem<MergedNode>, u8), WaCustomError> {
+    let mut node = vec_store.root_vec.item.clone().get().clone();
+    loop {
+        let Some(mut node_arc) = node.get_data() else {
             return Err(WaCustomError::NodeError(
-                "Current entry is null".to_string(),
-            ))
+                "Root node is not loaded".to_string(),
+            ));
+        };
+        let node_ref = node_arc.get();
+        let parent = node_ref.parent.item.clone().get().clone();
+        if parent.get_data().is_none() {
+            return Ok((node, node_ref.hnsw_level.0));
         }
+        node = parent;
+    }
+}
+
+// The nodes one level below the given ones, through their `child` links
+fn child_nodes(
+    nodes: &[(LazyItem<MergedNode>, MetricResult)],
+) -> Vec<(LazyItem<MergedNode>, MetricResult)> {
+    nodes
+        .iter()
+        .filter_map(|(node, dist)| {
+            let mut node_arc = node.get_data()?;
+            let child = node_arc.get().child.item.clone().get().clone();
+            child.get_data()?;
+            Some((child, dist.clone()))
+        })
+        .collect()
+}
+
+fn node_value(node: &LazyItem<MergedNode>) -> Result<Arc<Storage>, WaCustomError> {
+    let mut node_arc = node
+        .get_data()
+        .ok_or_else(|| WaCustomError::NodeError("Node is not loaded".to_string()))?;
+    let mut prop_arc = node_arc.get().prop.clone();
+    match prop_arc.get() {
+        PropState::Ready(prop) => Ok(prop.value.clone()),
+        PropState::Pending(_) => Err(WaCustomError::NodeError(
+            "Node prop is in pending state".to_string(),
+        )),
+    }
+}
+
+fn node_vector_id(node: &LazyItem<MergedNode>) -> Option<VectorId> {
+    let mut node_arc = node.get_data()?;
+    get_vector_id_from_node(node_arc.get())
+}
+
+// Picks at most `m` of the candidates as a node's neighbors, closest first,
+// with the collection's neighbor selection strategy
+fn select_neighbors(
+    vec_store: &VectorStore,
+    mut candidates: Vec<(LazyItem<MergedNode>, MetricResult)>,
+) -> Vec<(LazyItem<MergedNode>, MetricResult)> {
+    candidates.sort_by(|a, b| b.1.get_similarity().total_cmp(&a.1.get_similarity()));
+
+    let m = vec_store.hnsw_params.m;
+    let NeighborSelection::Heuristic { keep_pruned } = vec_store.hnsw_params.neighbor_selection
+    else {
+        candidates.truncate(m);
+        return candidates;
     };
 
-    let cur_node = cur_node_arc.get();
+    // candidates whose value can't be read are never considered covered
+    let values: Vec<Option<Arc<Storage>>> = candidates
+        .iter()
+        .map(|(node, _)| node_value(node).ok())
+        .collect();
+    let similarities: Vec<f32> = candidates
+        .iter()
+        .map(|(_, dist)| dist.get_similarity())
+        .collect();
+
+    let selected = heuristic_selection(&similarities, m, keep_pruned, |i, j| {
+        let (Some(a), Some(b)) = (&values[i], &values[j]) else {
+            return None;
+        };
+        vec_store
+            .distance_metric
+            .calculate(a, b)
+            .ok()
+            .map(|dist| dist.get_similarity())
+    });
 
-    let mut prop_arc = cur_node.prop.clone();
-    let prop_state = prop_arc.get();
+    selected
+        .into_iter()
+        .map(|i| candidates[i].clone())
+        .collect()
+}
 
-    let node_prop = match &*prop_state {
-        PropState::Ready(prop) => prop,
-        PropState::Pending(_) => {
-            return Err(WaCustomError::NodeError(
-                "Node prop is in pending state".to_string(),
-            ))
+// Indexes of the candidates, given their similarities to the node closest
+// first, that the heuristic keeps: a candidate is kept unless it's closer to a
+// candidate kept before it than to the node. `similarity(i, j)` is the
+// similarity between two candidates, if it's known
+fn heuristic_selection(
+    similarities: &[f32],
+    m: usize,
+    keep_pruned: bool,
+    mut similarity: impl FnMut(usize, usize) -> Option<f32>,
+) -> Vec<usize> {
+    let mut selected: Vec<usize> = Vec::with_capacity(m);
+    let mut pruned = Vec::new();
+
+    for (i, &node_similarity) in similarities.iter().enumerate() {
+        if selected.len() >= m {
+            break;
+        }
+        let covered = selected
+            .iter()
+            .any(|&j| similarity(i, j).map_or(false, |s| s > node_similarity));
+        if covered {
+            pruned.push(i);
+        } else {
+            selected.push(i);
         }
+    }
+
+    if keep_pruned {
+        let room = m.saturating_sub(selected.len());
+        selected.extend(pruned.into_iter().take(room));
+        selected.sort_unstable();
+    }
+
+    selected
+}
+
+fn is_tombstoned(vec_store: Arc<VectorStore>, node: &LazyItem<MergedNode>) -> bool {
+    let Some(mut node_arc) = node.get_data() else {
+        return false;
     };
+    match get_vector_id_from_node(node_arc.get()) {
+        Some(vector_id) => vec_store.tombstones.read().unwrap().contains(&vector_id),
+        None => false,
+    }
+}
 
-    let z = traverse_find_nearest(
-        vec_store.clone(),
-        cur_entry.clone(),
-        fvec.clone(),
-        vector_emb.hash_vec.clone(),
-        0,
-        &mut skipm,
-        cur_level,
-        false,
-    )?;
+// Whether the node belongs to an older embedding of its vector, which can
+// still be reached through edges that weren't unlinked when it was replaced.
+// Vectors without nodes in `vector_nodes`, e.g. since a restart, are trusted
+fn is_replaced(vec_store: &VectorStore, node: &LazyItem<MergedNode>) -> bool {
+    let Some(vector_id) = node_vector_id(node) else {
+        return false;
+    };
+    let current = match vec_store.vector_nodes.read().unwrap().get(&vector_id) {
+        // a node of an older embedding that was being indexed while it got
+        // replaced can be registered before the new one, never after
+        Some(nodes) => nodes.last().cloned(),
+        None => return false,
+    };
+    match (
+        current.map(|current| node_value(&current)),
+        node_value(node),
+    ) {
+        (Some(Ok(current)), Ok(value)) => current != value,
+        // the vector's old nodes were unlinked, and the new ones aren't indexed yet
+        (None, _) => true,
+        _ => false,
+    }
+}
 
-    let dist = vec_store
-        .distance_metric
-        .calculate(&fvec, &node_prop.value)?;
+fn node_matches_filter(
+    vec_store: Arc<VectorStore>,
+    node: &LazyItem<MergedNode>,
+    search_filter: &SearchFilter,
+) -> Result<bool, WaCustomError> {
+    let Some(mut node_arc) = node.get_data() else {
+        return Ok(false);
+    };
+    let Some(vector_id) = get_vector_id_from_node(node_arc.get()) else {
+        return Ok(false);
+    };
+    if let Some(candidates) = &search_filter.candidates {
+        let internal_id = vec_store
+            .payload_indexes
+            .read()
+            .unwrap()
+            .internal_id(&vector_id.to_string());
+        if !internal_id.map_or(false, |id| candidates.contains(id)) {
+            return Ok(false);
+        }
+    }
+    let metadata = retrieve_vector_metadata(vec_store, &vector_id)?;
+    Ok(search_filter.filter.matches(metadata.as_ref()))
+}
+
+// Exact search over the vectors whose metadata matches the filter, used when
+// the filter is too selective for the graph search to find `k` hits. Only the
+// candidates picked by the payload indexes are checked, if there are any
+pub fn filtered_scan(
+    vec_store: Arc<VectorStore>,
+    vector_emb: &VectorEmbedding,
+    search_filter: &SearchFilter,
+    k: usize,
+) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let payloads_db = vec_store.lmdb.payloads_db.clone();
+    let embedding_db = vec_store.lmdb.embeddings_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut offsets = Vec::new();
+    {
+        let mut check = |key: &[u8], value: &[u8]| -> Result<(), WaCustomError> {
+            let metadata: Metadata = serde_cbor::from_slice(value).map_err(|e| {
+                WaCustomError::DeserializationError(format!(
+                    "Failed to deserialize metadata: {}",
+                    e
+                ))
+            })?;
+            if !search_filter.filter.matches(Some(&metadata)) {
+                return Ok(());
+            }
+            match txn.get(*embedding_db, &key) {
+                Ok(bytes) => {
+                    let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                        WaCustomError::DeserializationError(e.to_string())
+                    })?;
+                    offsets.push(u32::from_le_bytes(bytes));
+                    Ok(())
+                }
+                // metadata stored without a matching embedding
+                Err(lmdb::Error::NotFound) => Ok(()),
+                Err(err) => Err(WaCustomError::DatabaseError(err.to_string())),
+            }
+        };
+
+        match &search_filter.candidates {
+            Some(candidates) => {
+                let keys: Vec<String> = {
+                    let payload_indexes = vec_store.payload_indexes.read().unwrap();
+                    candidates
+                        .iter()
+                        .filter_map(|id| payload_indexes.key(id).map(str::to_string))
+                        .collect()
+                };
+                for key in keys {
+                    match txn.get(*payloads_db, &key) {
+                        Ok(value) => check(key.as_bytes(), value)?,
+                        Err(lmdb::Error::NotFound) => continue,
+                        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+                    }
+                }
+            }
+            None => {
+                let mut cursor = txn
+                    .open_ro_cursor(*payloads_db)
+                    .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
 
-    let z = if z.is_empty() {
-        vec![(cur_entry.clone(), dist)]
-    } else {
-        z
+                for (key, value) in cursor.iter() {
+                    check(key, value)?;
+                }
+            }
+        }
+    }
+    txn.abort();
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let mut results = Vec::with_capacity(offsets.len());
+    for offset in offsets {
+        let (embedding, _) = read_embedding(&mut file, offset)?;
+        let dist = vec_store
+            .distance_metric
+            .calculate(&vector_emb.raw_vec, &embedding.raw_vec)?;
+        results.push((embedding.hash_vec, dist));
+    }
+
+    results.sort_by(|a, b| {
+        b.1.get_similarity()
+            .partial_cmp(&a.1.get_similarity())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    results.truncate(k);
+
+    Ok(results)
+}
+
+// Exact search over the embeddings that were stored but not indexed yet, from
+// `next_file_offset` to the end of `vec_raw.0`, so that they can be found
+// before they make it into the graph
+pub fn unindexed_scan(
+    vec_store: Arc<VectorStore>,
+    vector_emb: &VectorEmbedding,
+    filter: Option<&Filter>,
+    k: usize,
+) -> Result<Vec<(VectorId, MetricResult)>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let metadata_db = vec_store.lmdb.metadata_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let next_file_offset = match txn.get(*metadata_db, &"next_file_offset") {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => 0,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
     };
 
-    let result = ann_search(
-        vec_store.clone(),
-        vector_emb.clone(),
-        z[0].0.clone(),
-        cur_level - 1,
-    )?;
+    txn.abort();
+
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+    {
+        Ok(file) => file,
+        // nothing was inserted yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+    };
 
-    Ok(add_option_vecs(&result, &Some(z)))
+    let len = file
+        .metadata()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?
+        .len() as u32;
+
+    let mut results = Vec::new();
+    let mut i = next_file_offset;
+    while i < len {
+        // an upsert may still be writing the last embedding
+        let Ok((embedding, next)) = read_embedding(&mut file, i) else {
+            break;
+        };
+        let offset = i;
+        i = next;
+
+        // embeddings of deleted vectors, or replaced by a later upsert, are skipped
+        if embedding_offset(vec_store.clone(), &embedding.hash_vec)? != Some(offset) {
+            continue;
+        }
+        if let Some(filter) = filter {
+            let metadata = retrieve_vector_metadata(vec_store.clone(), &embedding.hash_vec)?;
+            if !filter.matches(metadata.as_ref()) {
+                continue;
+            }
+        }
+
+        let dist = vec_store
+            .distance_metric
+            .calculate(&vector_emb.raw_vec, &embedding.raw_vec)?;
+        results.push((embedding.hash_vec, dist));
+    }
+
+    results.sort_by(|a, b| {
+        b.1.get_similarity()
+            .partial_cmp(&a.1.get_similarity())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    results.truncate(k);
+
+    Ok(results)
+}
+
+// Exact search over the vectors of a past version of the collection. Their
+// embeddings are still in `vec_raw.0`, which is only ever appended to
+pub fn snapshot_scan(
+    vec_store: Arc<VectorStore>,
+    vector_emb: &VectorEmbedding,
+    snapshot: &HashMap<String, VectorSnapshot>,
+    filter: Option<&Filter>,
+    k: usize,
+) -> Result<Vec<(VectorId, MetricResult, Option<Metadata>)>, WaCustomError> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let mut results = Vec::new();
+    for vector in snapshot.values() {
+        if filter.map_or(false, |filter| !filter.matches(vector.metadata.as_ref())) {
+            continue;
+        }
+        let (embedding, _) = read_embedding(&mut file, vector.offset)?;
+        let dist = vec_store
+            .distance_metric
+            .calculate(&vector_emb.raw_vec, &embedding.raw_vec)?;
+        results.push((embedding.hash_vec, dist, vector.metadata.clone()));
+    }
+
+    results.sort_by(|a, b| {
+        b.1.get_similarity()
+            .partial_cmp(&a.1.get_similarity())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    results.truncate(k);
+
+    Ok(results)
 }
 
 pub fn vector_fetch(
@@ -177,6 +698,86 @@ pub fn vector_fetch(
     }
     Ok(results)
 }
+// Offset in `vec_raw.0` of the current embedding of a vector. Embeddings
+// stored at any other offset for the same id are stale
+pub fn embedding_offset(
+    vec_store: Arc<VectorStore>,
+    vector_id: &VectorId,
+) -> Result<Option<u32>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let embedding_db = vec_store.lmdb.embeddings_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    match txn.get(*embedding_db, &vector_id.to_string()) {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            Ok(Some(u32::from_le_bytes(bytes)))
+        }
+        Err(lmdb::Error::NotFound) => Ok(None),
+        Err(err) => Err(WaCustomError::DatabaseError(err.to_string())),
+    }
+}
+
+pub fn embedding_exists(
+    vec_store: Arc<VectorStore>,
+    vector_id: &VectorId,
+) -> Result<bool, WaCustomError> {
+    Ok(embedding_offset(vec_store, vector_id)?.is_some())
+}
+
+// Reads the embedding stored at `offset`, which doesn't have to be the current
+// embedding of its vector
+pub fn fetch_embedding_at(
+    vec_store: Arc<VectorStore>,
+    offset: u32,
+) -> Result<VectorEmbedding, WaCustomError> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let (embedding, _) = read_embedding(&mut file, offset)?;
+
+    Ok(embedding)
+}
+
+// Reads the original values of the embedding stored at `offset`, `None` if
+// only its quantized form is kept
+pub fn fetch_values_at(
+    vec_store: Arc<VectorStore>,
+    offset: u32,
+) -> Result<Option<Vec<f32>>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let values_db = vec_store.lmdb.values_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let values_offset = match txn.get(*values_db, &offset.to_le_bytes()) {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => return Ok(None),
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_values.0"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    read_values(&mut file, values_offset).map(Some)
+}
+
 fn load_node_from_persist(
     _offset: FileIndex,
     _vec_store: &Arc<VectorStore>,
@@ -256,6 +857,43 @@ pub fn write_embedding<W: Write + Seek>(
     Ok(start)
 }
 
+pub fn write_values<W: Write + Seek>(writer: &mut W, values: &[f32]) -> Result<u32, WaCustomError> {
+    let start = writer
+        .stream_position()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))? as u32;
+
+    let mut buf = Vec::with_capacity(4 + values.len() * 4);
+    buf.write_u32::<LittleEndian>(values.len() as u32)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    for value in values {
+        buf.write_f32::<LittleEndian>(*value)
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    }
+
+    writer
+        .write_all(&buf)
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    Ok(start)
+}
+
+fn read_values<R: Read + Seek>(reader: &mut R, offset: u32) -> Result<Vec<f32>, WaCustomError> {
+    reader
+        .seek(SeekFrom::Start(offset as u64))
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    let len = reader
+        .read_u32::<LittleEndian>()
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    let mut values = vec![0.0; len as usize];
+    reader
+        .read_f32_into::<LittleEndian>(&mut values)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    Ok(values)
+}
+
 fn read_embedding<R: Read + Seek>(
     reader: &mut R,
     offset: u32,
@@ -268,84 +906,521 @@ fn read_embedding<R: Read + Seek>(
         .read_u32::<LittleEndian>()
         .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
 
-    let mut buf = vec![0; len as usize];
+    let mut buf = vec![0; len as usize];
+
+    reader
+        .read_exact(&mut buf)
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+
+    let emb = unsafe { rkyv::from_bytes_unchecked(&buf) }.map_err(|e| {
+        WaCustomError::DeserializationError(format!("Failed to deserialize VectorEmbedding: {}", e))
+    })?;
+
+    let next = reader
+        .stream_position()
+        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))? as u32;
+
+    Ok((emb, next))
+}
+
+// Appends the embedding to `vec_raw.0`, and the original values it was
+// quantized from to `vec_values.0`, and returns their offsets. Nothing refers
+// to them until the offsets are stored in LMDB, which the caller must do
+// while holding `append_lock`
+pub fn append_embedding(
+    vec_store: &VectorStore,
+    emb: &VectorEmbedding,
+    values: Option<&[f32]>,
+) -> Result<(u32, Option<u32>), WaCustomError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    // the position of a file opened for appending stays at 0 until it's
+    // written to, and the offsets are read from it
+    file.seek(SeekFrom::End(0))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let offset = write_embedding(&mut file, emb)?;
+
+    let values_offset = match values {
+        Some(values) => {
+            let mut values_file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(vec_store.collection_path.join("vec_values.0"))
+                .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+            values_file
+                .seek(SeekFrom::End(0))
+                .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+            Some(write_values(&mut values_file, values)?)
+        }
+        None => None,
+    };
+
+    Ok((offset, values_offset))
+}
+
+// Flushes the appended embeddings and values to disk
+fn sync_embeddings(vec_store: &VectorStore) -> Result<(), WaCustomError> {
+    for file_name in ["vec_raw.0", "vec_values.0"] {
+        let file = match OpenOptions::new()
+            .append(true)
+            .open(vec_store.collection_path.join(file_name))
+        {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+        };
+        file.sync_data()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    }
+
+    Ok(())
+}
+
+// Applies the changes of a new version of "main" and records the version, all
+// in a single LMDB transaction, so that readers see either all of it or none
+// of it. The in-memory state (payload indexes, tombstones, the graph) is only
+// updated once the transaction is committed. Embeddings referred to by the
+// changes must have been appended under `append_lock`, which must still be held
+pub fn commit_vector_writes(
+    vec_store: Arc<VectorStore>,
+    version: u32,
+    writes: &[VectorWrite],
+    indexing_offsets: Option<(u32, u32)>,
+) -> Result<(VersionHash, OperationCounts), WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let embedding_db = vec_store.lmdb.embeddings_db.clone();
+    let metadata_db = vec_store.lmdb.metadata_db.clone();
+    let payloads_db = vec_store.lmdb.payloads_db.clone();
+    let values_db = vec_store.lmdb.values_db.clone();
+
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let mut counts = OperationCounts::default();
+    let mut appended = 0;
+    // vectors whose old nodes have to be unlinked from the graph
+    let mut replaced = Vec::new();
+
+    for write in writes {
+        match write {
+            VectorWrite::Store {
+                id,
+                offset,
+                values_offset,
+                metadata,
+                appended: is_appended,
+            } => {
+                let key = id.to_string();
+                match txn.get(*embedding_db, &key) {
+                    Ok(_) => {
+                        counts.updated += 1;
+                        replaced.push(id.clone());
+                    }
+                    Err(lmdb::Error::NotFound) => counts.inserted += 1,
+                    Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+                }
+                txn.put(
+                    *embedding_db,
+                    &key,
+                    &offset.to_le_bytes(),
+                    WriteFlags::empty(),
+                )
+                .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+
+                if let Some(values_offset) = values_offset {
+                    txn.put(
+                        *values_db,
+                        &offset.to_le_bytes(),
+                        &values_offset.to_le_bytes(),
+                        WriteFlags::empty(),
+                    )
+                    .map_err(|e| {
+                        WaCustomError::DatabaseError(format!("Failed to put data: {}", e))
+                    })?;
+                }
+
+                match metadata {
+                    Some(metadata) => {
+                        let serialized = serde_cbor::to_vec(metadata).map_err(|e| {
+                            WaCustomError::SerializationError(format!("Failed to serialize: {}", e))
+                        })?;
+                        txn.put(*payloads_db, &key, &serialized, WriteFlags::empty())
+                            .map_err(|e| {
+                                WaCustomError::DatabaseError(format!("Failed to put data: {}", e))
+                            })?;
+                    }
+                    None => del_if_exists(&mut txn, *payloads_db, &key)?,
+                }
+
+                // an empty metadata records that the vector has none
+                put_vector_change(
+                    &mut txn,
+                    &vec_store.lmdb,
+                    version,
+                    id,
+                    &VectorChange {
+                        offset: Some(*offset),
+                        metadata: Some(metadata.clone().unwrap_or_default()),
+                    },
+                )?;
+
+                if *is_appended {
+                    appended += 1;
+                }
+            }
+            VectorWrite::Delete { id } => {
+                let key = id.to_string();
+                match txn.del(*embedding_db, &key, None) {
+                    Ok(()) => counts.deleted += 1,
+                    Err(lmdb::Error::NotFound) => continue,
+                    Err(e) => {
+                        return Err(WaCustomError::DatabaseError(format!(
+                            "Failed to delete data: {}",
+                            e
+                        )))
+                    }
+                }
+                del_if_exists(&mut txn, *payloads_db, &key)?;
+                put_vector_change(
+                    &mut txn,
+                    &vec_store.lmdb,
+                    version,
+                    id,
+                    &VectorChange {
+                        offset: None,
+                        metadata: None,
+                    },
+                )?;
+                replaced.push(id.clone());
+            }
+        }
+    }
+
+    match indexing_offsets {
+        Some((count_indexed, next_file_offset)) => {
+            put_indexing_offsets(&mut txn, *metadata_db, count_indexed, 0, next_file_offset)?
+        }
+        None => {
+            let (_, count_unindexed) = read_indexing_counts(&txn, *metadata_db)?;
+            txn.put(
+                *metadata_db,
+                &"count_unindexed",
+                &(count_unindexed + appended).to_le_bytes(),
+                WriteFlags::empty(),
+            )
+            .map_err(|e| {
+                WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
+            })?;
+        }
+    }
+
+    // the offsets must not point past what's on disk after a crash
+    if appended > 0 {
+        sync_embeddings(&vec_store)?;
+    }
 
-    reader
-        .read_exact(&mut buf)
-        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))?;
+    let mut hasher = vec_store.version_hasher.lock().unwrap();
+    let (version_hash, next_hasher) =
+        put_current_version(&mut txn, &vec_store.lmdb, &hasher, "main", version, counts)?;
 
-    let emb = unsafe { rkyv::from_bytes_unchecked(&buf) }.map_err(|e| {
-        WaCustomError::DeserializationError(format!("Failed to deserialize VectorEmbedding: {}", e))
+    // the version becomes visible here
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
     })?;
 
-    let next = reader
-        .stream_position()
-        .map_err(|e| WaCustomError::DeserializationError(e.to_string()))? as u32;
+    *hasher = next_hasher;
+    drop(hasher);
+
+    {
+        let mut payload_indexes = vec_store.payload_indexes.write().unwrap();
+        let mut tombstones = vec_store.tombstones.write().unwrap();
+        for write in writes {
+            match write {
+                VectorWrite::Store { id, metadata, .. } => {
+                    match metadata {
+                        Some(metadata) => payload_indexes.insert(&id.to_string(), metadata),
+                        None => payload_indexes.remove(&id.to_string()),
+                    }
+                    tombstones.remove(id);
+                }
+                VectorWrite::Delete { id } => {
+                    payload_indexes.remove(&id.to_string());
+                    tombstones.insert(id.clone());
+                }
+            }
+        }
+    }
+    vec_store.set_current_version(Some(version_hash.clone()));
+
+    // the version is visible by now, failing to unlink the old nodes doesn't
+    // undo it
+    for vector_id in &replaced {
+        if let Err(e) = remove_vector_nodes(vec_store.clone(), vector_id) {
+            log::error!(
+                "Failed to unlink the old nodes of vector {}: {}",
+                vector_id,
+                e
+            );
+        }
+    }
 
-    Ok((emb, next))
+    Ok((version_hash, counts))
 }
 
-pub fn insert_embedding(
+// Same as `commit_vector_writes` for a branch other than "main". The changes
+// are only recorded in the branch's history, where reads of the branch find
+// them, the current state and the graph of the collection are those of "main"
+pub fn commit_branch_writes(
     vec_store: Arc<VectorStore>,
-    emb: &VectorEmbedding,
-) -> Result<(), WaCustomError> {
+    branch: &str,
+    version: u32,
+    writes: &[VectorWrite],
+) -> Result<(VersionHash, OperationCounts), WaCustomError> {
+    // read before the write transaction is started, which LMDB doesn't allow
+    // in the same thread
+    let mut existed = Vec::with_capacity(writes.len());
+    for write in writes {
+        let id = match write {
+            VectorWrite::Store { id, .. } | VectorWrite::Delete { id } => id,
+        };
+        existed.push(
+            retrieve_branch_vector_as_of(vec_store.clone(), branch, id, version - 1)?.is_some(),
+        );
+    }
+
     let env = vec_store.lmdb.env.clone();
-    let embedding_db = vec_store.lmdb.embeddings_db.clone();
     let metadata_db = vec_store.lmdb.metadata_db.clone();
+    let values_db = vec_store.lmdb.values_db.clone();
 
     let mut txn = env
         .begin_rw_txn()
         .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-    let count_unindexed = match txn.get(*metadata_db, &"count_unindexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
-        }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
-
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open("vec_raw.0")
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    let mut counts = OperationCounts::default();
+    let mut appended = 0;
+
+    for (write, existed) in writes.iter().zip(existed) {
+        match write {
+            VectorWrite::Store {
+                id,
+                offset,
+                values_offset,
+                metadata,
+                appended: is_appended,
+            } => {
+                if existed {
+                    counts.updated += 1;
+                } else {
+                    counts.inserted += 1;
+                }
 
-    let offset = write_embedding(&mut file, emb)?.to_le_bytes();
+                if let Some(values_offset) = values_offset {
+                    txn.put(
+                        *values_db,
+                        &offset.to_le_bytes(),
+                        &values_offset.to_le_bytes(),
+                        WriteFlags::empty(),
+                    )
+                    .map_err(|e| {
+                        WaCustomError::DatabaseError(format!("Failed to put data: {}", e))
+                    })?;
+                }
 
-    txn.put(
-        *embedding_db,
-        &emb.hash_vec.to_string(),
-        &offset,
-        WriteFlags::empty(),
-    )
-    .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+                put_branch_vector_change(
+                    &mut txn,
+                    &vec_store.lmdb,
+                    branch,
+                    version,
+                    id,
+                    &VectorChange {
+                        offset: Some(*offset),
+                        metadata: Some(metadata.clone().unwrap_or_default()),
+                    },
+                )?;
+
+                if *is_appended {
+                    appended += 1;
+                }
+            }
+            VectorWrite::Delete { id } => {
+                if !existed {
+                    continue;
+                }
+                counts.deleted += 1;
+                put_branch_vector_change(
+                    &mut txn,
+                    &vec_store.lmdb,
+                    branch,
+                    version,
+                    id,
+                    &VectorChange {
+                        offset: None,
+                        metadata: None,
+                    },
+                )?;
+            }
+        }
+    }
 
+    // the embeddings are in the tail of `vec_raw.0` that indexing goes
+    // through, which skips them as they aren't the current ones of "main"
+    let (_, count_unindexed) = read_indexing_counts(&txn, *metadata_db)?;
     txn.put(
         *metadata_db,
         &"count_unindexed",
-        &(count_unindexed + 1).to_le_bytes(),
+        &(count_unindexed + appended).to_le_bytes(),
         WriteFlags::empty(),
     )
     .map_err(|e| {
         WaCustomError::DatabaseError(format!("Failed to update `count_unindexed`: {}", e))
     })?;
 
+    if appended > 0 {
+        sync_embeddings(&vec_store)?;
+    }
+
+    let mut hasher = vec_store.version_hasher.lock().unwrap();
+    let (version_hash, next_hasher) =
+        put_current_version(&mut txn, &vec_store.lmdb, &hasher, branch, version, counts)?;
+
     txn.commit().map_err(|e| {
         WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
     })?;
 
+    *hasher = next_hasher;
+
+    Ok((version_hash, counts))
+}
+
+fn del_if_exists(
+    txn: &mut lmdb::RwTransaction,
+    db: lmdb::Database,
+    key: &str,
+) -> Result<(), WaCustomError> {
+    match txn.del(db, &key, None) {
+        Ok(()) | Err(lmdb::Error::NotFound) => Ok(()),
+        Err(e) => Err(WaCustomError::DatabaseError(format!(
+            "Failed to delete data: {}",
+            e
+        ))),
+    }
+}
+
+// Unlinks the vector's nodes from the graph at every level. Each former
+// neighbor is reconnected to the closest of the removed node's other
+// neighbors, so that the part of the graph reached through it stays reachable
+pub fn remove_vector_nodes(
+    vec_store: Arc<VectorStore>,
+    vector_id: &VectorId,
+) -> Result<(), WaCustomError> {
+    // the emptied entry tells search that none of the vector's old nodes are
+    // current anymore, including nodes loaded since a restart that aren't
+    // tracked here
+    let nodes = std::mem::take(
+        vec_store
+            .vector_nodes
+            .write()
+            .unwrap()
+            .entry(vector_id.clone())
+            .or_default(),
+    );
+
+    for node in nodes {
+        let Some(mut node_arc) = node.get_data() else {
+            continue;
+        };
+
+        let neighbors: Vec<LazyItem<MergedNode>> = node_arc
+            .get()
+            .neighbors
+            .iter()
+            .map(|nbr| nbr.1)
+            .filter(|nbr| !is_tombstoned(vec_store.clone(), nbr))
+            .collect();
+
+        for nbr in &neighbors {
+            let Some(mut nbr_arc) = nbr.get_data() else {
+                continue;
+            };
+            let nbr_node = nbr_arc.get();
+            let Some(nbr_id) = get_vector_id_from_node(nbr_node) else {
+                continue;
+            };
+
+            nbr_node.neighbors.retain(|item| {
+                item.1
+                    .get_data()
+                    .and_then(|mut arc| get_vector_id_from_node(arc.get()))
+                    .as_ref()
+                    != Some(vector_id)
+            });
+
+            repair_neighbors(vec_store.clone(), nbr_node, &nbr_id, &neighbors)?;
+        }
+    }
+
     Ok(())
 }
 
-pub fn index_embeddings(
+// Links the node to the closest of the candidates it isn't linked to yet,
+// at least one and at most as many as it has room for
+fn repair_neighbors(
     vec_store: Arc<VectorStore>,
-    upload_process_batch_size: usize,
+    node: &MergedNode,
+    node_id: &VectorId,
+    candidates: &[LazyItem<MergedNode>],
 ) -> Result<(), WaCustomError> {
+    let mut prop_arc = node.prop.clone();
+    let PropState::Ready(node_prop) = prop_arc.get() else {
+        return Ok(());
+    };
+
+    let linked: HashSet<VectorId> = node
+        .neighbors
+        .iter()
+        .filter_map(|nbr| nbr.1.get_data())
+        .filter_map(|mut arc| get_vector_id_from_node(arc.get()))
+        .collect();
+
+    let mut new_neighbors = Vec::new();
+    for candidate in candidates {
+        let Some(mut candidate_arc) = candidate.get_data() else {
+            continue;
+        };
+        let mut candidate_prop_arc = candidate_arc.get().prop.clone();
+        let PropState::Ready(candidate_prop) = candidate_prop_arc.get() else {
+            continue;
+        };
+        if &candidate_prop.id == node_id || linked.contains(&candidate_prop.id) {
+            continue;
+        }
+
+        let dist = vec_store
+            .distance_metric
+            .calculate(&node_prop.value, &candidate_prop.value)?;
+        new_neighbors.push((candidate.clone(), dist));
+    }
+
+    new_neighbors.sort_by(|a, b| {
+        b.1.get_similarity()
+            .partial_cmp(&a.1.get_similarity())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    new_neighbors.truncate(vec_store.hnsw_params.m.saturating_sub(linked.len()).max(1));
+
+    node.add_ready_neighbors(new_neighbors);
+
+    Ok(())
+}
+
+// Returns how many embeddings are in the graph, and how many were stored but
+// not indexed yet
+pub fn indexing_counts(vec_store: Arc<VectorStore>) -> Result<(u32, u32), WaCustomError> {
     let env = vec_store.lmdb.env.clone();
     let metadata_db = vec_store.lmdb.metadata_db.clone();
 
@@ -353,27 +1428,42 @@ pub fn index_embeddings(
         .begin_ro_txn()
         .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-    let mut count_indexed = match txn.get(*metadata_db, &"count_indexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
-        }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
+    read_indexing_counts(&txn, *metadata_db)
+}
 
-    let mut count_unindexed = match txn.get(*metadata_db, &"count_unindexed") {
-        Ok(bytes) => {
-            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
-                WaCustomError::DeserializationError(e.to_string())
-            })?;
-            u32::from_le_bytes(bytes)
-        }
-        Err(lmdb::Error::NotFound) => 0,
-        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
-    };
+fn read_indexing_counts(
+    txn: &impl Transaction,
+    metadata_db: lmdb::Database,
+) -> Result<(u32, u32), WaCustomError> {
+    let mut counts = [0; 2];
+    for (count, key) in counts.iter_mut().zip(["count_indexed", "count_unindexed"]) {
+        *count = match txn.get(metadata_db, &key) {
+            Ok(bytes) => {
+                let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                    WaCustomError::DeserializationError(e.to_string())
+                })?;
+                u32::from_le_bytes(bytes)
+            }
+            Err(lmdb::Error::NotFound) => 0,
+            Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+        };
+    }
+
+    Ok((counts[0], counts[1]))
+}
+
+// Indexes the embeddings stored after `next_file_offset` and returns how many
+// were added to the graph. The caller must hold `index_lock`
+pub fn index_embeddings(
+    vec_store: Arc<VectorStore>,
+    upload_process_batch_size: usize,
+) -> Result<u32, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let metadata_db = vec_store.lmdb.metadata_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
     let next_file_offset = match txn.get(*metadata_db, &"next_file_offset") {
         Ok(bytes) => {
@@ -390,53 +1480,51 @@ pub fn index_embeddings(
 
     let mut file = OpenOptions::new()
         .read(true)
-        .open("vec_raw.0")
+        .open(vec_store.collection_path.join("vec_raw.0"))
         .map_err(|e| WaCustomError::FsError(e.to_string()))?;
 
-    let metadata = file
-        .metadata()
-        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
-    let len = metadata.len() as u32;
+    // embeddings appended after this point are left to the next run
+    let len = {
+        let _append_guard = vec_store.append_lock.lock().unwrap();
+        file.metadata()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?
+            .len() as u32
+    };
 
     let mut i = next_file_offset;
     let mut embeddings = Vec::new();
 
+    let mut read = 0;
+    let mut indexed = 0;
+
     // `file` is not thread safe, so we have to collect all the embeddings in the current thread
     while i < len {
         let (embedding, next) = read_embedding(&mut file, i)?;
-        embeddings.push(embedding);
+        // embeddings of deleted vectors, or replaced by a later upsert, are skipped
+        if embedding_offset(vec_store.clone(), &embedding.hash_vec)? == Some(i) {
+            embeddings.push((embedding, i));
+        }
+        read += 1;
         i = next;
 
-        if embeddings.len() == upload_process_batch_size || i == len {
-            // TODO: handle the errors
-            let results: Vec<()> = embeddings
-                .into_par_iter()
-                .map(|embedding| {
-                    let lp = &vec_store.levels_prob;
-                    let iv = get_max_insert_level(rand::random::<f32>().into(), lp.clone());
-
-                    index_embedding(
-                        vec_store.clone(),
-                        None,
-                        embedding,
-                        vec_store.root_vec.item.clone().get().clone(),
-                        vec_store.max_cache_level.try_into().unwrap(),
-                        iv.try_into().unwrap(),
-                    )
-                    .expect("index_embedding failed");
-                })
-                .collect();
-
+        if read == upload_process_batch_size || i == len {
+            let batch_size = index_embeddings_batch(vec_store.clone(), embeddings, |id| {
+                embedding_offset(vec_store.clone(), id)
+            })?;
             embeddings = Vec::new();
-
-            let batch_size = results.len() as u32;
-            count_indexed += batch_size;
-            count_unindexed -= batch_size;
+            indexed += batch_size;
 
             let mut txn = env.begin_rw_txn().map_err(|e| {
                 WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e))
             })?;
 
+            // the counts are read again as upserts keep storing embeddings
+            // while they're indexed
+            let (count_indexed, count_unindexed) = read_indexing_counts(&txn, *metadata_db)?;
+            let count_indexed = count_indexed + batch_size;
+            let count_unindexed = count_unindexed.saturating_sub(read as u32);
+            read = 0;
+
             txn.put(
                 *metadata_db,
                 &"count_indexed",
@@ -467,131 +1555,375 @@ pub fn index_embeddings(
                 WaCustomError::DatabaseError(format!("Failed to update `next_file_offset`: {}", e))
             })?;
 
-            txn.commit().map_err(|e| {
-                WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+            txn.commit().map_err(|e| {
+                WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+            })?;
+        }
+    }
+
+    Ok(indexed)
+}
+
+// Number of vectors currently stored in the collection
+pub fn embeddings_count(vec_store: Arc<VectorStore>) -> Result<u32, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let embedding_db = vec_store.lmdb.embeddings_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+    let mut cursor = txn
+        .open_ro_cursor(*embedding_db)
+        .map_err(|e| WaCustomError::DatabaseError(e.to_string()))?;
+
+    Ok(cursor.iter().count() as u32)
+}
+
+// Indexes the embeddings in `vec_raw.0` that `current_offset` tells are the
+// current ones of their vector, used to build a graph from scratch. `proceed`
+// is called with the number of embeddings indexed so far after each batch,
+// and the build stops if it returns false. Returns how many embeddings were
+// indexed, the offset they were read up to and the offset each vector was
+// indexed from, or None if the build was stopped
+pub fn build_index(
+    vec_store: Arc<VectorStore>,
+    upload_process_batch_size: usize,
+    current_offset: impl Fn(&VectorId) -> Result<Option<u32>, WaCustomError> + Sync,
+    mut proceed: impl FnMut(u32) -> bool,
+) -> Result<Option<(u32, u32, HashMap<VectorId, u32>)>, WaCustomError> {
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+    {
+        Ok(file) => file,
+        // nothing was inserted yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Some((0, 0, HashMap::new())))
+        }
+        Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+    };
+
+    let len = {
+        let _append_guard = vec_store.append_lock.lock().unwrap();
+        file.metadata()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?
+            .len() as u32
+    };
+
+    let mut i = 0;
+    let mut embeddings = Vec::new();
+    let mut indexed = 0;
+    let mut offsets = HashMap::new();
+
+    while i < len {
+        let (embedding, next) = read_embedding(&mut file, i)?;
+        if current_offset(&embedding.hash_vec)? == Some(i) {
+            offsets.insert(embedding.hash_vec.clone(), i);
+            embeddings.push((embedding, i));
+        }
+        i = next;
+
+        if embeddings.len() == upload_process_batch_size || i >= len {
+            indexed += index_embeddings_batch(vec_store.clone(), embeddings, &current_offset)?;
+            embeddings = Vec::new();
+
+            if !proceed(indexed) {
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(Some((indexed, len, offsets)))
+}
+
+// Number of embeddings stored in `vec_raw.0` from `offset` on. The caller
+// must hold `append_lock`
+pub fn count_embeddings_from(vec_store: &VectorStore, offset: u32) -> Result<u32, WaCustomError> {
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(WaCustomError::FsError(e.to_string())),
+    };
+    let len = file
+        .metadata()
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?
+        .len() as u32;
+
+    let mut i = offset;
+    let mut count = 0;
+    while i < len {
+        let (_, next) = read_embedding(&mut file, i)?;
+        count += 1;
+        i = next;
+    }
+
+    Ok(count)
+}
+
+// Records that everything up to `next_file_offset` is in the graph, and that
+// `count_unindexed` embeddings were stored after it
+pub fn store_indexing_offsets(
+    vec_store: Arc<VectorStore>,
+    count_indexed: u32,
+    count_unindexed: u32,
+    next_file_offset: u32,
+) -> Result<(), WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let metadata_db = vec_store.lmdb.metadata_db.clone();
+
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    put_indexing_offsets(
+        &mut txn,
+        *metadata_db,
+        count_indexed,
+        count_unindexed,
+        next_file_offset,
+    )?;
+
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
+
+    Ok(())
+}
+
+fn put_indexing_offsets(
+    txn: &mut lmdb::RwTransaction,
+    metadata_db: lmdb::Database,
+    count_indexed: u32,
+    count_unindexed: u32,
+    next_file_offset: u32,
+) -> Result<(), WaCustomError> {
+    for (key, value) in [
+        ("count_indexed", count_indexed),
+        ("count_unindexed", count_unindexed),
+        ("next_file_offset", next_file_offset),
+    ] {
+        txn.put(metadata_db, &key, &value.to_le_bytes(), WriteFlags::empty())
+            .map_err(|e| {
+                WaCustomError::DatabaseError(format!("Failed to update `{}`: {}", key, e))
+            })?;
+    }
+
+    Ok(())
+}
+
+// Re-indexes the embeddings that had already been indexed before the last shutdown
+// (everything before `next_file_offset`), so that the in-memory graph can be rebuilt.
+// Their props are already in `prop.data`, and aren't written again
+pub fn reindex_embeddings(
+    vec_store: Arc<VectorStore>,
+    upload_process_batch_size: usize,
+) -> Result<(), WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let metadata_db = vec_store.lmdb.metadata_db.clone();
+
+    let txn = env
+        .begin_ro_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
+
+    let next_file_offset = match txn.get(*metadata_db, &"next_file_offset") {
+        Ok(bytes) => {
+            let bytes = bytes.try_into().map_err(|e: TryFromSliceError| {
+                WaCustomError::DeserializationError(e.to_string())
+            })?;
+            u32::from_le_bytes(bytes)
+        }
+        Err(lmdb::Error::NotFound) => 0,
+        Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+    };
+
+    txn.abort();
+
+    if next_file_offset == 0 {
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(vec_store.collection_path.join("vec_raw.0"))
+        .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+
+    let mut i = 0;
+    let mut embeddings = Vec::new();
+
+    while i < next_file_offset {
+        let (embedding, next) = read_embedding(&mut file, i)?;
+        if embedding_offset(vec_store.clone(), &embedding.hash_vec)? == Some(i) {
+            embeddings.push((embedding, i));
+        }
+        i = next;
+
+        if embeddings.len() == upload_process_batch_size || i >= next_file_offset {
+            index_embeddings_batch(vec_store.clone(), embeddings, |id| {
+                embedding_offset(vec_store.clone(), id)
             })?;
+            embeddings = Vec::new();
         }
     }
 
+    // the replayed nodes are already present in the index files, so they
+    // must not be queued for persistence a second time
+    vec_store.exec_queue_nodes.clone().update(Vec::new());
+
     Ok(())
 }
 
-pub fn index_embedding(
+// Indexes the embeddings, each read at the given offset, and returns how many
+// were added to the graph. `current_offset` is checked again right before
+// each insertion, as the vector may have been replaced or deleted since the
+// embedding was read
+fn index_embeddings_batch(
     vec_store: Arc<VectorStore>,
-    parent: Option<LazyItem<MergedNode>>,
-    vector_emb: VectorEmbedding,
-    cur_entry: LazyItem<MergedNode>,
-    cur_level: i8,
-    max_insert_level: i8,
-) -> Result<(), WaCustomError> {
-    if cur_level == -1 {
-        return Ok(());
-    }
+    embeddings: Vec<(VectorEmbedding, u32)>,
+    current_offset: impl Fn(&VectorId) -> Result<Option<u32>, WaCustomError> + Sync,
+) -> Result<u32, WaCustomError> {
+    let prop_locations = store_props(&vec_store, &embeddings)?;
+    let results = embeddings
+        .into_par_iter()
+        .zip(prop_locations)
+        .map(|((embedding, offset), prop_location)| {
+            if current_offset(&embedding.hash_vec)? != Some(offset) {
+                return Ok(false);
+            }
 
-    let fvec = vector_emb.raw_vec.clone();
-    let mut skipm = HashSet::new();
-    skipm.insert(vector_emb.hash_vec.clone());
+            let lp = &vec_store.levels_prob;
+            let iv = get_max_insert_level(rand::random::<f32>().into(), lp.clone());
+            let max_insert_level = iv
+                .try_into()
+                .map_err(|_| WaCustomError::NodeError(format!("Invalid insert level {}", iv)))?;
+
+            index_embedding(
+                vec_store.clone(),
+                embedding,
+                prop_location,
+                max_insert_level,
+            )?;
+            Ok(true)
+        })
+        .collect::<Result<Vec<bool>, WaCustomError>>()?;
 
-    let mut cur_node_arc = match cur_entry.clone() {
-        LazyItem::Valid {
-            data: Some(node), ..
-        } => node,
-        LazyItem::Valid {
-            data: None,
-            mut file_index,
-            ..
-        } => {
-            if let Some(file_index) = file_index.get() {
-                match file_index {
-                    FileIndex::Valid { offset, .. } => {
-                        return Err(WaCustomError::LazyLoadingError(format!(
-                            "Node at offset {} needs to be loaded",
-                            offset.0
-                        )));
-                    }
-                    FileIndex::Invalid => {
-                        return Err(WaCustomError::NodeError(
-                            "Current entry is null".to_string(),
-                        ));
-                    }
-                }
-            } else {
-                return Err(WaCustomError::NodeError(
-                    "Current entry is null".to_string(),
-                ));
-            }
-        }
-        _ => {
-            return Err(WaCustomError::NodeError(
-                "Current entry is null".to_string(),
-            ))
-        }
-    };
+    Ok(results.into_iter().filter(|indexed| *indexed).count() as u32)
+}
 
-    let cur_node = cur_node_arc.get();
+// Returns where the props of the embeddings, each read at the given offset,
+// are stored in `prop.data`. A prop is only written the first time its
+// embedding is indexed, and found in `props_db` when the graph is rebuilt, on
+// startup or by an index build
+fn store_props(
+    vec_store: &VectorStore,
+    embeddings: &[(VectorEmbedding, u32)],
+) -> Result<Vec<(FileOffset, BytesToRead)>, WaCustomError> {
+    let env = vec_store.lmdb.env.clone();
+    let props_db = vec_store.lmdb.props_db.clone();
 
-    let mut prop_arc = cur_node.prop.clone();
-    let prop_state = prop_arc.get();
+    // the write transaction also keeps props from being appended concurrently
+    let mut txn = env
+        .begin_rw_txn()
+        .map_err(|e| WaCustomError::DatabaseError(format!("Failed to begin transaction: {}", e)))?;
 
-    let node_prop = match &*prop_state {
-        PropState::Ready(prop) => prop,
-        PropState::Pending(_) => {
-            return Err(WaCustomError::NodeError(
-                "Node prop is in pending state".to_string(),
-            ))
-        }
-    };
+    let mut locations = Vec::with_capacity(embeddings.len());
+    let mut written = false;
+    for (embedding, offset) in embeddings {
+        let location = match txn.get(*props_db, &offset.to_le_bytes()) {
+            Ok(bytes) => {
+                let bytes: [u8; 8] = bytes.try_into().map_err(|e: TryFromSliceError| {
+                    WaCustomError::DeserializationError(e.to_string())
+                })?;
+                (
+                    FileOffset(u32::from_le_bytes(bytes[..4].try_into().unwrap())),
+                    BytesToRead(u32::from_le_bytes(bytes[4..].try_into().unwrap())),
+                )
+            }
+            Err(lmdb::Error::NotFound) => {
+                let prop = NodeProp {
+                    id: embedding.hash_vec.clone(),
+                    value: embedding.raw_vec.clone(),
+                    location: None,
+                };
+                let location = write_prop_to_file(&prop, &vec_store.prop_file);
+                let mut bytes = location.0 .0.to_le_bytes().to_vec();
+                bytes.extend_from_slice(&location.1 .0.to_le_bytes());
+                txn.put(
+                    *props_db,
+                    &offset.to_le_bytes(),
+                    &bytes,
+                    WriteFlags::empty(),
+                )
+                .map_err(|e| WaCustomError::DatabaseError(format!("Failed to put data: {}", e)))?;
+                written = true;
+                location
+            }
+            Err(err) => return Err(WaCustomError::DatabaseError(err.to_string())),
+        };
+        locations.push(location);
+    }
 
-    let z = traverse_find_nearest(
-        vec_store.clone(),
-        cur_entry.clone(),
-        fvec.clone(),
-        vector_emb.hash_vec.clone(),
-        0,
-        &mut skipm,
-        cur_level,
-        true,
-    )?;
+    // the locations must not point past what's on disk after a crash
+    if written {
+        vec_store
+            .prop_file
+            .sync_data()
+            .map_err(|e| WaCustomError::FsError(e.to_string()))?;
+    }
 
-    let dist = vec_store
-        .distance_metric
-        .calculate(&fvec, &node_prop.value)?;
+    txn.commit().map_err(|e| {
+        WaCustomError::DatabaseError(format!("Failed to commit transaction: {}", e))
+    })?;
 
-    let z = if z.is_empty() {
-        vec![(cur_entry.clone(), dist)]
-    } else {
-        z
-    };
+    Ok(locations)
+}
 
-    let z_clone: Vec<_> = z.iter().map(|(first, _)| first.clone()).collect();
+// Inserts the embedding into the graph, at every level from `max_insert_level`
+// down to 0. The levels above are only searched for the entry to the level below
+pub fn index_embedding(
+    vec_store: Arc<VectorStore>,
+    vector_emb: VectorEmbedding,
+    prop_location: (FileOffset, BytesToRead),
+    max_insert_level: u8,
+) -> Result<(), WaCustomError> {
+    let fvec = vector_emb.raw_vec.clone();
+    let (entry, level) = top_root(&vec_store)?;
+    let dist = vec_store
+        .distance_metric
+        .calculate(&fvec, &*node_value(&entry)?)?;
 
-    if cur_level <= max_insert_level {
-        let parent = insert_node_create_edges(
-            vec_store.clone(),
-            parent,
-            fvec,
-            vector_emb.hash_vec.clone(),
-            z,
-            cur_level,
-        )
-        .expect("Failed insert_node_create_edges");
-        index_embedding(
-            vec_store.clone(),
-            Some(parent),
-            vector_emb.clone(),
-            z_clone[0].clone(),
-            cur_level - 1,
-            max_insert_level,
-        )?;
-    } else {
-        index_embedding(
+    let mut entries = vec![(entry, dist)];
+    let mut parent = None;
+    for level in (0..=level).rev() {
+        let ef = if level <= max_insert_level {
+            vec_store.hnsw_params.ef_construction
+        } else {
+            1
+        };
+        let nearest = search_layer(
             vec_store.clone(),
-            None,
-            vector_emb.clone(),
-            z_clone[0].clone(),
-            cur_level - 1,
-            max_insert_level,
+            &fvec,
+            entries,
+            ef,
+            Some(&vector_emb.hash_vec),
         )?;
+
+        if level <= max_insert_level && !nearest.is_empty() {
+            let neighbors = select_neighbors(&vec_store, nearest.clone());
+            parent = Some(insert_node_create_edges(
+                vec_store.clone(),
+                parent,
+                fvec.clone(),
+                vector_emb.hash_vec.clone(),
+                prop_location,
+                neighbors,
+                level as i8,
+            )?);
+        }
+
+        entries = child_nodes(&nearest);
     }
 
     Ok(())
@@ -599,7 +1931,6 @@ pub fn index_embedding(
 
 pub fn queue_node_prop_exec(
     lznode: LazyItem<MergedNode>,
-    prop_file: Arc<File>,
     vec_store: Arc<VectorStore>,
 ) -> Result<(), WaCustomError> {
     let (mut node_arc, _location) = match &lznode {
@@ -637,10 +1968,8 @@ pub fn queue_node_prop_exec(
 
     let prop_state = prop_arc.get();
 
-    if let PropState::Ready(node_prop) = &*prop_state {
-        let prop_location = write_prop_to_file(node_prop, &prop_file);
-        node.set_prop_location(prop_location);
-    } else {
+    // the prop was stored when its embedding was first indexed
+    if !matches!(&*prop_state, PropState::Ready(node_prop) if node_prop.location.is_some()) {
         return Err(WaCustomError::NodeError(
             "Node prop is not ready".to_string(),
         ));
@@ -692,13 +2021,14 @@ fn insert_node_create_edges(
     parent: Option<LazyItem<MergedNode>>,
     fvec: Arc<Storage>,
     hs: VectorId,
+    prop_location: (FileOffset, BytesToRead),
     nbs: Vec<(LazyItem<MergedNode>, MetricResult)>,
     cur_level: i8,
 ) -> Result<LazyItem<MergedNode>, WaCustomError> {
     let node_prop = NodeProp {
         id: hs.clone(),
         value: fvec.clone(),
-        location: None,
+        location: Some(prop_location),
     };
     let mut nn = ArcShift::new(MergedNode::new(HNSWLevel(cur_level as u8)));
     nn.get().set_prop_ready(Arc::new(node_prop));
@@ -713,24 +2043,31 @@ fn insert_node_create_edges(
             ..
         } = nbr1.clone()
         {
+            let nbr1_node = nbr1_node.get();
+            if nbr1_node.neighbors.len() < vec_store.hnsw_params.m {
+                nbr1_node.add_ready_neighbor(lz_item.clone(), cs);
+                continue;
+            }
+
             let mut neighbor_list: Vec<(LazyItem<MergedNode>, MetricResult)> = nbr1_node
-                .get()
                 .neighbors
                 .iter()
                 .map(|nbr2| (nbr2.1, nbr2.0))
                 .collect();
+            neighbor_list.push((lz_item.clone(), cs.clone()));
 
-            neighbor_list.push((lz_item.clone(), cs));
-
-            neighbor_list.sort_by(|a, b| {
-                b.1.get_value()
-                    .partial_cmp(&a.1.get_value())
-                    .unwrap_or(std::cmp::Ordering::Equal)
-            });
-
-            neighbor_list.truncate(20);
-
-            nbr1_node.get().add_ready_neighbors(neighbor_list);
+            // the neighbor is full, so it only keeps the selected ones, which
+            // may or may not include the new node
+            let selected: HashSet<VectorId> = select_neighbors(&vec_store, neighbor_list)
+                .iter()
+                .filter_map(|(node, _)| node_vector_id(node))
+                .collect();
+            nbr1_node
+                .neighbors
+                .retain(|item| node_vector_id(&item.1).map_or(false, |id| selected.contains(&id)));
+            if selected.contains(&hs) {
+                nbr1_node.add_ready_neighbor(lz_item.clone(), cs);
+            }
         }
     }
     println!("insert node create edges, queuing nodes");
@@ -738,152 +2075,180 @@ fn insert_node_create_edges(
         lz_item.get_data().unwrap().set_parent(parent.clone());
         parent.get_data().unwrap().set_child(lz_item.clone());
     }
-    queue_node_prop_exec(lz_item.clone(), vec_store.prop_file.clone(), vec_store)?;
+    vec_store
+        .vector_nodes
+        .write()
+        .unwrap()
+        .entry(hs)
+        .or_default()
+        .push(lz_item.clone());
+    queue_node_prop_exec(lz_item.clone(), vec_store)?;
 
     Ok(lz_item)
 }
 
-fn traverse_find_nearest(
-    vec_store: Arc<VectorStore>,
-    vtm: LazyItem<MergedNode>,
-    fvec: Arc<Storage>,
-    hs: VectorId,
-    hops: u8,
-    skipm: &mut HashSet<VectorId>,
-    cur_level: i8,
-    skip_hop: bool,
-) -> Result<Vec<(LazyItem<MergedNode>, MetricResult)>, WaCustomError> {
-    let mut tasks: SmallVec<[Vec<(LazyItem<MergedNode>, MetricResult)>; 24]> = SmallVec::new();
-
-    let mut node_arc = match vtm.clone() {
-        LazyItem::Valid {
-            data: Some(node), ..
-        } => node,
-        LazyItem::Valid {
-            data: None,
-            mut file_index,
-            ..
-        } => {
-            if let Some(file_index) = file_index.get() {
-                match file_index {
-                    FileIndex::Valid { offset, .. } => {
-                        return Err(WaCustomError::LazyLoadingError(format!(
-                            "Node at offset {} needs to be loaded",
-                            offset.0
-                        )));
-                    }
-                    FileIndex::Invalid => {
-                        return Err(WaCustomError::NodeError(
-                            "Current entry is null".to_string(),
-                        ));
-                    }
-                }
-            } else {
-                return Err(WaCustomError::NodeError(
-                    "Current entry is null".to_string(),
-                ));
-            }
-        }
-        _ => {
-            return Err(WaCustomError::NodeError(
-                "Current entry is null".to_string(),
-            ))
-        }
-    };
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, io::Cursor, path::Path, sync::Arc};
 
-    let node = node_arc.get();
+    use futures::executor::block_on;
+    use rand::{distributions::Uniform, rngs::ThreadRng, thread_rng, Rng};
+    use tempfile::tempdir;
 
-    for (index, nref) in node.neighbors.iter().enumerate() {
-        if let Some(mut neighbor_arc) = nref.1.get_data() {
-            let neighbor = neighbor_arc.get();
-            let mut prop_arc = neighbor.prop.clone();
-            let prop_state = prop_arc.get();
+    use crate::{
+        api_service::{
+            ann_vector_query, commit_transaction, create_vector_store, open_transaction,
+            rollback_to_version, stage_operations,
+        },
+        distance::DistanceFunction,
+        models::{
+            common::remove_duplicates_and_filter,
+            meta_persist::{create_branch, retrieve_branch_vectors_as_of},
+            rpc::VersionSelector,
+            types::{
+                get_app_env, init_app_env, DistanceMetric, HnswParams, MetricResult,
+                QuantizationMetric, StagedOperation, VectorEmbedding, VectorId, VectorStore,
+                VectorWrite,
+            },
+        },
+        quantization::{scalar::ScalarQuantization, Quantization, StorageType},
+    };
 
-            let node_prop = match prop_state {
-                PropState::Ready(prop) => prop.clone(),
-                PropState::Pending(loc) => {
-                    return Err(WaCustomError::NodeError(format!(
-                        "Neighbor prop is in pending state at loc: {:?}",
-                        loc
-                    )))
-                }
-            };
+    use super::{
+        ann_search, append_embedding, commit_branch_writes, commit_vector_writes,
+        heuristic_selection, index_embeddings, indexing_counts, node_value, node_vector_id,
+        read_embedding, read_values, reindex_embeddings, write_embedding, write_values,
+    };
 
-            let nb = node_prop.id.clone();
+    const DIMENSIONS: usize = 16;
 
-            if index % 2 != 0 && skip_hop && index > 4 {
-                continue;
-            }
+    // A collection compared with the euclidean distance, which is exact for
+    // half precision storage, so that results can be checked against a brute
+    // force search
+    fn test_store(dir: &Path, hnsw_params: HnswParams) -> Arc<VectorStore> {
+        create_vector_store(
+            "test".to_string(),
+            dir.to_path_buf(),
+            DIMENSIONS,
+            None,
+            None,
+            5,
+            QuantizationMetric::Scalar,
+            DistanceMetric::Euclidean,
+            StorageType::HalfPrecisionFP,
+            Vec::new(),
+            hnsw_params,
+        )
+        .unwrap()
+    }
 
-            let vec_store = vec_store.clone();
-            let fvec = fvec.clone();
-            let hs = hs.clone();
+    fn random_vectors(rng: &mut ThreadRng, count: usize) -> Vec<(VectorId, Vec<f32>)> {
+        let range = Uniform::new(-1.0, 1.0);
+        (0..count)
+            .map(|i| {
+                let values = (0..DIMENSIONS).map(|_| rng.sample(&range)).collect();
+                (VectorId::Int(i as i32), values)
+            })
+            .collect()
+    }
 
-            if skipm.insert(nb.clone()) {
-                let dist = vec_store
-                    .distance_metric
-                    .calculate(&fvec, &node_prop.value)?;
-
-                let full_hops = 30;
-                if hops <= tapered_total_hops(full_hops, cur_level as u8, vec_store.max_cache_level)
-                {
-                    let mut z = traverse_find_nearest(
-                        vec_store.clone(),
-                        nref.1.clone(),
-                        fvec.clone(),
-                        hs.clone(),
-                        hops + 1,
-                        skipm,
-                        cur_level,
-                        skip_hop,
-                    )?;
-                    z.push((nref.1.clone(), dist));
-                    tasks.push(z);
-                } else {
-                    tasks.push(vec![(nref.1.clone(), dist)]);
-                }
-            }
+    fn quantized(vec_store: &VectorStore, id: VectorId, values: &[f32]) -> VectorEmbedding {
+        VectorEmbedding {
+            raw_vec: Arc::new(
+                vec_store
+                    .quantization_metric
+                    .quantize(values, vec_store.storage_type),
+            ),
+            hash_vec: id,
         }
     }
 
-    let mut nn: Vec<_> = tasks.into_iter().flatten().collect();
-    nn.sort_by(|a, b| b.1.get_value().partial_cmp(&a.1.get_value()).unwrap());
-    let mut seen = HashSet::new();
-    nn.retain(|(lazy_node, _)| {
-        if let LazyItem::Valid {
-            data: Some(node_arc),
-            ..
-        } = &lazy_node
-        {
-            let mut node_arc = node_arc.clone();
-            let node = node_arc.get();
-            let mut prop_arc = node.prop.clone();
-            let prop_state = prop_arc.get();
-            if let PropState::Ready(node_prop) = &*prop_state {
-                seen.insert(node_prop.id.clone())
-            } else {
-                false
-            }
-        } else {
-            false
-        }
-    });
+    // Appends the embeddings of the vectors, the caller holds `append_lock`
+    fn append_writes(
+        vec_store: &Arc<VectorStore>,
+        vectors: &[(VectorId, Vec<f32>)],
+    ) -> Vec<VectorWrite> {
+        vectors
+            .iter()
+            .map(|(id, values)| {
+                let embedding = quantized(vec_store, id.clone(), values);
+                let (offset, values_offset) =
+                    append_embedding(vec_store, &embedding, Some(values)).unwrap();
+                VectorWrite::Store {
+                    id: id.clone(),
+                    offset,
+                    values_offset,
+                    metadata: None,
+                    appended: true,
+                }
+            })
+            .collect()
+    }
 
-    Ok(nn.into_iter().take(5).collect())
-}
+    // Stores the vectors as the next version of "main", without indexing them
+    fn store_vectors(vec_store: &Arc<VectorStore>, vectors: &[(VectorId, Vec<f32>)]) {
+        let version = vec_store.get_current_version().unwrap().version + 1;
+        let _append_guard = vec_store.append_lock.lock().unwrap();
+        let writes = append_writes(vec_store, vectors);
+        commit_vector_writes(vec_store.clone(), version, &writes, None).unwrap();
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::{io::Cursor, sync::Arc};
+    fn search(vec_store: &Arc<VectorStore>, query: &[f32], k: usize, ef: usize) -> Vec<VectorId> {
+        let embedding = quantized(vec_store, VectorId::Str("query".to_string()), query);
+        let results = ann_search(vec_store.clone(), embedding, k, ef, None).unwrap();
+        remove_duplicates_and_filter(results, k)
+            .unwrap()
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect()
+    }
 
-    use rand::{distributions::Uniform, rngs::ThreadRng, thread_rng, Rng};
+    // Searches the way `/search` does, including the vectors that aren't
+    // indexed yet
+    fn query(vec_store: &Arc<VectorStore>, query: &[f32], k: usize) -> Vec<VectorId> {
+        block_on(ann_vector_query(
+            vec_store.clone(),
+            query.to_vec(),
+            k,
+            None,
+            None,
+            "main",
+            None,
+        ))
+        .unwrap()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(id, _, _)| id)
+        .collect()
+    }
 
-    use crate::{
-        models::types::{VectorEmbedding, VectorId},
-        quantization::{scalar::ScalarQuantization, Quantization, StorageType},
-    };
+    fn brute_force(
+        vec_store: &VectorStore,
+        vectors: &[(VectorId, Vec<f32>)],
+        query: &[f32],
+        k: usize,
+    ) -> Vec<VectorId> {
+        let query = quantized(vec_store, VectorId::Str("query".to_string()), query);
+        let mut results: Vec<(VectorId, MetricResult)> = vectors
+            .iter()
+            .map(|(id, values)| {
+                let embedding = quantized(vec_store, id.clone(), values);
+                let dist = vec_store
+                    .distance_metric
+                    .calculate(&query.raw_vec, &embedding.raw_vec)
+                    .unwrap();
+                (id.clone(), dist)
+            })
+            .collect();
+        results.sort_by(|a, b| b.1.get_similarity().total_cmp(&a.1.get_similarity()));
+        results.into_iter().take(k).map(|(id, _)| id).collect()
+    }
 
-    use super::{read_embedding, write_embedding};
+    // share of the exact nearest neighbors found by the search
+    fn recall(found: &[VectorId], exact: &[VectorId]) -> f32 {
+        let found = found.iter().filter(|id| exact.contains(id)).count();
+        found as f32 / exact.len() as f32
+    }
 
     fn get_random_embedding(rng: &mut ThreadRng) -> VectorEmbedding {
         let range = Uniform::new(-1.0, 1.0);
@@ -936,4 +2301,403 @@ mod tests {
             assert_eq!(embedding, deserialized);
         }
     }
+
+    #[test]
+    fn test_values_serialization() {
+        let values = [0.25f32, -1.0, 0.125];
+
+        let mut writer = Cursor::new(Vec::new());
+        write_values(&mut writer, &[1.0]).unwrap();
+        let offset = write_values(&mut writer, &values).unwrap();
+
+        let mut reader = Cursor::new(writer.into_inner());
+        assert_eq!(read_values(&mut reader, offset).unwrap(), values);
+    }
+
+    #[test]
+    fn test_heuristic_selection() {
+        // points on a line, the node being at 0 and the similarity between two
+        // points the opposite of their distance
+        let points = [1.0f32, -1.5, 2.0, 3.0];
+        let similarities: Vec<f32> = points.iter().map(|p| -p.abs()).collect();
+        let similarity = |i: usize, j: usize| Some(-(points[i] - points[j]).abs());
+
+        // 2 and 3 are closer to 1 than to the node
+        assert_eq!(
+            heuristic_selection(&similarities, 3, false, similarity),
+            vec![0, 1]
+        );
+        assert_eq!(
+            heuristic_selection(&similarities, 3, true, similarity),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            heuristic_selection(&similarities, 1, true, similarity),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn test_default_params_recall() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let mut rng = thread_rng();
+        let vectors = random_vectors(&mut rng, 500);
+        store_vectors(&vec_store, &vectors);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        assert_eq!(index_embeddings(vec_store.clone(), 100).unwrap(), 500);
+        drop(index_guard);
+
+        let queries = random_vectors(&mut rng, 20);
+        let total: f32 = queries
+            .iter()
+            .map(|(_, query)| {
+                let found = search(&vec_store, query, 10, vec_store.hnsw_params.ef_search);
+                recall(&found, &brute_force(&vec_store, &vectors, query, 10))
+            })
+            .sum();
+        let recall = total / queries.len() as f32;
+        assert!(recall >= 0.9, "recall {} is below 0.9", recall);
+    }
+
+    #[test]
+    fn test_reindex_reuses_props() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 50);
+        store_vectors(&vec_store, &vectors);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+
+        let props_len = vec_store.prop_file.metadata().unwrap().len();
+        reindex_embeddings(vec_store.clone(), 100).unwrap();
+        assert_eq!(vec_store.prop_file.metadata().unwrap().len(), props_len);
+
+        let (id, values) = &vectors[7];
+        assert_eq!(search(&vec_store, values, 1, 50), vec![id.clone()]);
+    }
+
+    #[test]
+    fn test_branch_writes_leave_main_unchanged() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 3);
+        store_vectors(&vec_store, &vectors);
+        let main_head = vec_store.get_current_version().unwrap();
+        create_branch(vec_store.clone(), "experiment", &main_head).unwrap();
+
+        // vector 0 is replaced and vector 1 deleted on the branch
+        let replacement = (VectorId::Int(0), vec![0.5; DIMENSIONS]);
+        let _append_guard = vec_store.append_lock.lock().unwrap();
+        let mut writes = append_writes(&vec_store, &[replacement]);
+        writes.push(VectorWrite::Delete {
+            id: VectorId::Int(1),
+        });
+        let (head, counts) =
+            commit_branch_writes(vec_store.clone(), "experiment", 2, &writes).unwrap();
+        assert_eq!((counts.updated, counts.deleted), (1, 1));
+        assert_eq!(head.branch, "experiment");
+
+        let branch = retrieve_branch_vectors_as_of(vec_store.clone(), "experiment", 2).unwrap();
+        let main = retrieve_branch_vectors_as_of(vec_store.clone(), "main", 1).unwrap();
+        assert_eq!(branch.len(), 2);
+        assert_eq!(main.len(), 3);
+        assert_ne!(branch["0"].offset, main["0"].offset);
+        assert_eq!(branch["2"].offset, main["2"].offset);
+
+        // the branch at the version it was created from is "main" at that version
+        let forked = retrieve_branch_vectors_as_of(vec_store.clone(), "experiment", 1).unwrap();
+        assert_eq!(forked.len(), 3);
+        assert_eq!(vec_store.get_current_version().unwrap(), main_head);
+    }
+
+    #[test]
+    fn test_search_returns_exactly_k() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 200);
+        store_vectors(&vec_store, &vectors);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+
+        // more results than `ef` are asked for, and every node is reached
+        // through several others
+        let found = search(&vec_store, &vectors[0].1, 60, 20);
+        assert_eq!(found.len(), 60);
+        assert_eq!(found.iter().collect::<HashSet<_>>().len(), 60);
+        assert_eq!(found[0], vectors[0].0);
+    }
+
+    #[test]
+    fn test_staged_writes_are_invisible_until_commit() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 20);
+        store_vectors(&vec_store, &vectors);
+
+        let permit = vec_store.write_lock.clone().try_acquire_owned().unwrap();
+        let transaction = open_transaction(&vec_store, "main", permit).unwrap();
+        let staged = vec![0.5; DIMENSIONS];
+        stage_operations(
+            vec_store.clone(),
+            &transaction.hash,
+            vec![
+                StagedOperation::Upsert {
+                    id: VectorId::Int(100),
+                    values: staged.clone(),
+                    metadata: None,
+                },
+                StagedOperation::Delete {
+                    id: VectorId::Int(0),
+                },
+            ],
+        )
+        .unwrap();
+
+        assert!(!query(&vec_store, &staged, 5).contains(&VectorId::Int(100)));
+        assert_eq!(query(&vec_store, &vectors[0].1, 1), vec![VectorId::Int(0)]);
+        assert_eq!(vec_store.get_current_version().unwrap().version, 1);
+    }
+
+    #[test]
+    fn test_commit_makes_staged_writes_searchable() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 20);
+        store_vectors(&vec_store, &vectors);
+
+        let permit = vec_store.write_lock.clone().try_acquire_owned().unwrap();
+        let transaction = open_transaction(&vec_store, "main", permit).unwrap();
+        let staged = vec![0.5; DIMENSIONS];
+        stage_operations(
+            vec_store.clone(),
+            &transaction.hash,
+            vec![
+                StagedOperation::Upsert {
+                    id: VectorId::Int(100),
+                    values: staged.clone(),
+                    metadata: None,
+                },
+                StagedOperation::Delete {
+                    id: VectorId::Int(0),
+                },
+            ],
+        )
+        .unwrap();
+        commit_transaction(vec_store.clone(), transaction, 100).unwrap();
+
+        assert_eq!(query(&vec_store, &staged, 1), vec![VectorId::Int(100)]);
+        assert!(!query(&vec_store, &vectors[0].1, 5).contains(&VectorId::Int(0)));
+        assert_eq!(vec_store.get_current_version().unwrap().version, 2);
+        // the committed version was indexed along with the one before it
+        assert_eq!(indexing_counts(vec_store.clone()).unwrap(), (20, 0));
+        assert!(vec_store.write_lock.clone().try_acquire_owned().is_ok());
+    }
+
+    #[test]
+    fn test_rollback_restores_earlier_results() {
+        // the rebuilt graph is swapped in through the collections map, under a
+        // name no other test uses
+        init_app_env(&tempdir().unwrap().into_path()).unwrap();
+        let dir = tempdir().unwrap();
+        let vec_store = create_vector_store(
+            "rollback".to_string(),
+            dir.path().to_path_buf(),
+            DIMENSIONS,
+            None,
+            None,
+            5,
+            QuantizationMetric::Scalar,
+            DistanceMetric::Euclidean,
+            StorageType::HalfPrecisionFP,
+            Vec::new(),
+            HnswParams::default(),
+        )
+        .unwrap();
+        let mut rng = thread_rng();
+        let vectors = random_vectors(&mut rng, 30);
+        store_vectors(&vec_store, &vectors);
+
+        // version 2 adds vectors and replaces vector 0
+        let added: Vec<_> = random_vectors(&mut rng, 60).split_off(30);
+        let replacement = (VectorId::Int(0), vec![0.5; DIMENSIONS]);
+        store_vectors(
+            &vec_store,
+            &[added.clone(), vec![replacement.clone()]].concat(),
+        );
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+        assert_eq!(query(&vec_store, &replacement.1, 1), vec![VectorId::Int(0)]);
+
+        let rolled_back =
+            rollback_to_version(vec_store.clone(), &VersionSelector::Number(1), 100).unwrap();
+        assert_eq!(rolled_back.version, 3);
+
+        let vec_store = get_app_env()
+            .unwrap()
+            .vector_store_map
+            .get("rollback")
+            .unwrap()
+            .clone();
+        for (id, values) in &vectors {
+            assert_eq!(&query(&vec_store, values, 1), &[id.clone()]);
+        }
+        for (id, values) in &added {
+            assert!(!query(&vec_store, values, 5).contains(id));
+        }
+    }
+
+    #[test]
+    fn test_delete_repairs_neighbor_lists() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 100);
+        store_vectors(&vec_store, &vectors);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+
+        let deleted: HashSet<VectorId> = (0..20).map(VectorId::Int).collect();
+        // each deleted vector with the nodes it linked to
+        let former_neighbors: Vec<_> = deleted
+            .iter()
+            .flat_map(|id| {
+                let nodes = vec_store.vector_nodes.read().unwrap()[id].clone();
+                nodes.into_iter().flat_map(move |node| {
+                    let mut node_arc = node.get_data().unwrap();
+                    let neighbors: Vec<_> = node_arc
+                        .get()
+                        .neighbors
+                        .iter()
+                        .map(|nbr| (id.clone(), nbr.1))
+                        .collect();
+                    neighbors
+                })
+            })
+            .filter(|(_, nbr)| node_vector_id(nbr).map_or(false, |id| !deleted.contains(&id)))
+            .collect();
+
+        let writes: Vec<VectorWrite> = deleted
+            .iter()
+            .map(|id| VectorWrite::Delete { id: id.clone() })
+            .collect();
+        let append_guard = vec_store.append_lock.lock().unwrap();
+        commit_vector_writes(vec_store.clone(), 2, &writes, None).unwrap();
+        drop(append_guard);
+
+        // the former neighbors are unlinked from the deleted vectors and
+        // linked to others instead. Edges that only went one way are left to
+        // searches to purge
+        for (id, node) in &former_neighbors {
+            let mut node_arc = node.get_data().unwrap();
+            let neighbors: Vec<VectorId> = node_arc
+                .get()
+                .neighbors
+                .iter()
+                .filter_map(|nbr| node_vector_id(&nbr.1))
+                .collect();
+            assert!(!neighbors.contains(id));
+            // the upper levels may have no other node to link to
+            if node_arc.get().hnsw_level.0 == 0 {
+                assert!(!neighbors.is_empty());
+            }
+        }
+        assert!(deleted
+            .iter()
+            .all(|id| vec_store.vector_nodes.read().unwrap()[id].is_empty()));
+
+        for (id, values) in &vectors[20..] {
+            assert_eq!(&search(&vec_store, values, 1, 50), &[id.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_upsert_replaces_the_node() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let vectors = random_vectors(&mut thread_rng(), 50);
+        store_vectors(&vec_store, &vectors);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+
+        let replacement = (VectorId::Int(0), vec![0.5; DIMENSIONS]);
+        store_vectors(&vec_store, &[replacement.clone()]);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        assert_eq!(index_embeddings(vec_store.clone(), 100).unwrap(), 1);
+        drop(index_guard);
+
+        let nodes = vec_store.vector_nodes.read().unwrap()[&replacement.0].clone();
+        let level_0: Vec<_> = nodes
+            .iter()
+            .filter(|node| node.get_data().unwrap().get().hnsw_level.0 == 0)
+            .collect();
+        assert_eq!(level_0.len(), 1);
+        let value = quantized(&vec_store, replacement.0.clone(), &replacement.1).raw_vec;
+        assert_eq!(node_value(level_0[0]).unwrap(), value);
+
+        // the old node isn't found, even next to its old values
+        for query in [&vectors[0].1, &replacement.1] {
+            let embedding = quantized(&vec_store, VectorId::Str("query".to_string()), query);
+            let results = ann_search(vec_store.clone(), embedding, 10, 50, None)
+                .unwrap()
+                .unwrap();
+            let found: Vec<_> = results
+                .iter()
+                .filter(|(node, _)| node_vector_id(node) == Some(replacement.0.clone()))
+                .collect();
+            assert_eq!(found.len(), 1);
+            assert_eq!(node_value(&found[0].0).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_unindexed_vectors_are_searchable() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let mut rng = thread_rng();
+        let vectors = random_vectors(&mut rng, 30);
+        store_vectors(&vec_store, &vectors[..20]);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+        store_vectors(&vec_store, &vectors[20..]);
+        assert_eq!(indexing_counts(vec_store.clone()).unwrap(), (20, 10));
+
+        // the hits of the graph and of the unindexed tail are merged
+        for (_, values) in random_vectors(&mut rng, 5) {
+            assert_eq!(
+                query(&vec_store, &values, 10),
+                brute_force(&vec_store, &vectors, &values, 10)
+            );
+        }
+        for (id, values) in &vectors[20..] {
+            assert!(!search(&vec_store, values, 5, 50).contains(id));
+            assert_eq!(&query(&vec_store, values, 1), &[id.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_exhaustive_search_is_exact() {
+        let dir = tempdir().unwrap();
+        let vec_store = test_store(dir.path(), HnswParams::default());
+        let mut rng = thread_rng();
+        let vectors = random_vectors(&mut rng, 200);
+        store_vectors(&vec_store, &vectors);
+        let index_guard = vec_store.index_lock.lock().unwrap();
+        index_embeddings(vec_store.clone(), 100).unwrap();
+        drop(index_guard);
+
+        // with `ef` covering the whole collection the best-first search
+        // visits every node of level 0, so nothing can be missed
+        for (_, values) in random_vectors(&mut rng, 10) {
+            assert_eq!(
+                search(&vec_store, &values, 10, vectors.len()),
+                brute_force(&vec_store, &vectors, &values, 10)
+            );
+        }
+    }
 }
@@ -15,6 +15,7 @@ use std::{fs::File, io::BufReader};
 use cosdata::config_loader::{load_config, ServerMode, Ssl, Host};
 use actix_web::web::Data;
 
+use crate::api_service::load_collections;
 use crate::models::types::*;
 use crate::{api, WaCustomError};
 use std::env;
@@ -52,6 +53,15 @@ pub async fn run_actix_server() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
     let config = load_config();
 
+    if let Err(e) = init_app_env(&config.data_dir) {
+        log::error!("Failed to initialize the app env: {}", e);
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+    }
+
+    if let Err(e) = load_collections(&config) {
+        log::error!("Failed to load collections: {}", e);
+    }
+
     let tls = match &config.server.mode {
         ServerMode::Https => Some(load_rustls_config(&config.server.ssl)),
         ServerMode::Http => {
@@ -90,8 +100,45 @@ pub async fn run_actix_server() -> std::io::Result<()> {
                         web::resource("/createdb").route(web::post().to(api::vectordb::create)),
                     )
                     .service(web::resource("/upsert").route(web::post().to(api::vectordb::upsert)))
+                    .service(web::resource("/delete").route(web::post().to(api::vectordb::delete)))
                     .service(web::resource("/search").route(web::post().to(api::vectordb::search)))
                     .service(web::resource("/fetch").route(web::post().to(api::vectordb::fetch)))
+                    .service(
+                        web::scope("/collections")
+                            .route("", web::get().to(api::vectordb::collections::list))
+                            .route(
+                                "/{collection_name}",
+                                web::get().to(api::vectordb::collections::get),
+                            )
+                            .route(
+                                "/{collection_name}",
+                                web::delete().to(api::vectordb::collections::delete),
+                            ),
+                    )
+                    .service(
+                        web::scope("{database_name}/index")
+                            .route("", web::post().to(api::vectordb::index::create))
+                            .route("", web::get().to(api::vectordb::index::build))
+                            .route("", web::delete().to(api::vectordb::index::cancel))
+                            .route("/status", web::get().to(api::vectordb::index::status)),
+                    )
+                    .service(
+                        web::scope("{database_name}/branches")
+                            .route("", web::get().to(api::vectordb::branches::list))
+                            .route("", web::post().to(api::vectordb::branches::create)),
+                    )
+                    .service(
+                        web::scope("{database_name}/versions")
+                            .route("", web::get().to(api::vectordb::versions::list))
+                            .route(
+                                "/{from}/diff/{to}",
+                                web::get().to(api::vectordb::versions::diff),
+                            )
+                            .route(
+                                "/{version}/rollback",
+                                web::post().to(api::vectordb::versions::rollback),
+                            ),
+                    )
                     .service(
                         web::scope("{database_name}/transactions")
                             .route("/", web::post().to(api::vectordb::transactions::create))